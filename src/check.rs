@@ -0,0 +1,137 @@
+// * `config check`: exercise everything the daemon depends on and say how to fix what fails
+use anyhow::{anyhow, Context};
+use mongodb::bson::doc;
+use mongodb::Client;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use crate::geo::{self, GeoError};
+use crate::{lkup, read_config, Config, ConfigSource};
+
+// * well known address with geo data and a PTR record
+const PROBE_IP: &str = "8.8.8.8";
+const MONGO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// * print one result line; true if the check passed
+fn report(name: &str, result: anyhow::Result<String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("ok    {name}: {detail}");
+            true
+        }
+        Err(e) => {
+            println!("FAIL  {name}: {e:#}");
+            false
+        }
+    }
+}
+
+fn geo_hint(e: &GeoError) -> &'static str {
+    match e {
+        GeoError::Status(status) if status.as_u16() == 401 || status.as_u16() == 403 => {
+            "api_key was rejected by ipgeolocation.io; check api_key or api_key_file"
+        }
+        GeoError::Status(status) if status.as_u16() == 429 => {
+            "ipgeolocation.io quota exceeded; wait or upgrade the plan"
+        }
+        GeoError::Status(_) => "ipgeolocation.io returned an error; try again later",
+        GeoError::Request(_) => "could not reach api.ipgeolocation.io; check network and proxy",
+        GeoError::Decode(_) => {
+            "unexpected response from ipgeolocation.io; the api may have changed"
+        }
+    }
+}
+
+async fn check_mongo(config: &Config) -> anyhow::Result<String> {
+    let client = Client::with_uri_str(&config.db_uri)
+        .await
+        .context("db_uri could not be parsed")?;
+    let db = client.database(&config.db_name);
+    match timeout(MONGO_TIMEOUT, db.run_command(doc! {"ping": 1}, None)).await {
+        Ok(Ok(_)) => Ok(format!("reached {}", config.db_name)),
+        Ok(Err(e)) => Err(anyhow!(e).context("ping failed; check db_uri credentials")),
+        Err(_) => Err(anyhow!(
+            "no answer in {MONGO_TIMEOUT:?}; is mongod running and is db_uri's host reachable?"
+        )),
+    }
+}
+
+async fn check_indexes(config: &Config) -> anyhow::Result<String> {
+    crate::setup_db(config).await.context(
+        "index creation failed; the db user needs createIndex, or run `loglook db migrate`",
+    )?;
+    Ok("indexes in place".to_string())
+}
+
+async fn check_geo(config: &Config) -> anyhow::Result<String> {
+    let geodata = geo::fetch_geodata(PROBE_IP, &config.api_key)
+        .await
+        .map_err(|e| anyhow!("{}: {e}", geo_hint(&e)))?;
+    Ok(format!(
+        "ipgeolocation.io placed {PROBE_IP} in {}",
+        geodata.country_name
+    ))
+}
+
+async fn check_ptr() -> anyhow::Result<String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    lkup::lkup_hostnames(PROBE_IP, tx).await;
+    let data = rx.recv().await.expect("lookup always sends a result");
+    match data.ptr_records.first().map(String::as_str) {
+        Some("timed out") => Err(anyhow!(
+            "reverse lookup of {PROBE_IP} timed out; check the nameservers in /etc/resolv.conf"
+        )),
+        Some("unknown") | None => Err(anyhow!(
+            "no PTR record for {PROBE_IP}; the resolver may be filtering reverse lookups"
+        )),
+        Some(_) => Ok(format!("{PROBE_IP} is {}", data.ptr_records.join(", "))),
+    }
+}
+
+pub async fn check(source: &ConfigSource) -> anyhow::Result<()> {
+    let path = source
+        .path
+        .as_ref()
+        .map_or("~/.loglook/config.toml".to_string(), |p| {
+            p.display().to_string()
+        });
+    let config = match read_config(source) {
+        Ok(config) => config,
+        Err(e) => {
+            report(
+                "config",
+                Err(e.context("fix the file, or choose another with --config/--profile")),
+            );
+            return Err(anyhow!("config check failed"));
+        }
+    };
+    let mut ok = report("config", Ok(format!("parsed {path}")));
+    ok &= report("settings", config.validate().map(|_| "valid".to_string()));
+    // * index creation needs a reachable server, so only try it after a good ping
+    if report("mongodb", check_mongo(&config).await) {
+        ok &= report("indexes", check_indexes(&config).await);
+    } else {
+        ok = false;
+    }
+    ok &= report("geo lookup", check_geo(&config).await);
+    ok &= report("reverse dns", check_ptr().await);
+    if ok {
+        Ok(())
+    } else {
+        Err(anyhow!("config check failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_key_names_the_setting() {
+        let e = GeoError::Status(reqwest::StatusCode::UNAUTHORIZED);
+        assert!(geo_hint(&e).contains("api_key"));
+        let e = GeoError::Status(reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert!(geo_hint(&e).contains("quota"));
+    }
+}
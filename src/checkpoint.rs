@@ -0,0 +1,81 @@
+// * remember how far each logfile has been read so that a cycle resumes where the last one stopped
+use mongodb::bson::doc;
+use mongodb::options::ReplaceOptions;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(rename = "_id")]
+    pub path: String,
+    pub inode: i64,
+    pub offset: i64,
+    pub updated: bson::DateTime,
+}
+
+// * key checkpoints by absolute path so relative invocations share them
+pub(crate) fn path_key(path: &Path) -> String {
+    path.canonicalize()
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn inode(path: &Path) -> anyhow::Result<u64> {
+    Ok(std::fs::metadata(path)?.ino())
+}
+
+// * offset to resume reading at; starts over if the file was rotated or truncated
+pub fn start_offset(saved: Option<&Checkpoint>, inode: u64, len: u64) -> u64 {
+    match saved {
+        Some(cp) if cp.inode as u64 == inode && cp.offset as u64 <= len => cp.offset as u64,
+        _ => 0,
+    }
+}
+
+pub async fn load(db: &Database, path: &Path) -> anyhow::Result<Option<Checkpoint>> {
+    let coll: Collection<Checkpoint> = db.collection("checkpoints");
+    Ok(coll.find_one(doc! {"_id": path_key(path)}, None).await?)
+}
+
+pub async fn save(db: &Database, path: &Path, inode: u64, offset: u64) -> anyhow::Result<()> {
+    let coll: Collection<Checkpoint> = db.collection("checkpoints");
+    let checkpoint = Checkpoint {
+        path: path_key(path),
+        inode: inode as i64,
+        offset: offset as i64,
+        updated: chrono::Utc::now().into(),
+    };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    coll.replace_one(doc! {"_id": &checkpoint.path}, &checkpoint, options)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(inode: i64, offset: i64) -> Checkpoint {
+        Checkpoint {
+            path: "/var/log/nginx/access.log".to_string(),
+            inode,
+            offset,
+            updated: bson::DateTime::now(),
+        }
+    }
+
+    #[test]
+    fn resumes_same_file() {
+        assert_eq!(start_offset(Some(&checkpoint(7, 100)), 7, 150), 100);
+        assert_eq!(start_offset(None, 7, 150), 0);
+    }
+
+    #[test]
+    fn restarts_rotated_or_truncated_file() {
+        assert_eq!(start_offset(Some(&checkpoint(7, 100)), 8, 150), 0);
+        assert_eq!(start_offset(Some(&checkpoint(7, 100)), 7, 50), 0);
+    }
+}
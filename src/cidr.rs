@@ -0,0 +1,130 @@
+// * ip addresses as sortable binary keys, for CIDR range queries over IPv4 and IPv6 alike
+use anyhow::bail;
+use bson::spec::BinarySubtype;
+use bson::Binary;
+use ipnet::IpNet;
+use mongodb::bson::{doc, Document};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// * IPv4 is stored IPv4-mapped (::ffff:a.b.c.d) so both families share one 16 byte key space
+fn key_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+fn binary(bytes: [u8; 16]) -> Binary {
+    Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: bytes.to_vec(),
+    }
+}
+
+// * mongo orders same-length, same-subtype binaries bytewise, which is address order
+pub fn ip_key(ip: &str) -> Option<Binary> {
+    ip.parse::<IpAddr>().ok().map(|ip| binary(key_bytes(ip)))
+}
+
+// * stands in for a missing ip_key; shorter binaries sort before every key
+pub(crate) fn no_key() -> Binary {
+    Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: vec![],
+    }
+}
+
+// * condition matching an ip_key field inside any of nets
+pub(crate) fn in_any(nets: &[IpNet]) -> Document {
+    let ranges: Vec<Document> = nets
+        .iter()
+        .map(|net| {
+            let (low, high) = (key_bytes(net.network()), key_bytes(net.broadcast()));
+            doc! {"ip_key": doc! {"$gte": binary(low), "$lte": binary(high)}}
+        })
+        .collect();
+    doc! {"$or": ranges}
+}
+
+// * prefix lengths for grouping, e.g. "24" or "24,48"; IPv6 defaults to /64
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefixLens {
+    pub v4: u8,
+    pub v6: u8,
+}
+
+impl FromStr for PrefixLens {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<PrefixLens> {
+        let (v4, v6) = match spec.split_once(',') {
+            Some((v4, v6)) => (v4.trim(), v6.trim()),
+            None => (spec.trim(), "64"),
+        };
+        match (v4.parse::<u8>(), v6.parse::<u8>()) {
+            (Ok(v4 @ 0..=32), Ok(v6 @ 0..=128)) => Ok(PrefixLens { v4, v6 }),
+            _ => bail!("Bad prefix lengths {spec:?}; expected e.g. 24 or 24,48"),
+        }
+    }
+}
+
+impl PrefixLens {
+    // * the network of the given length containing ip
+    pub fn network(&self, ip: IpAddr) -> IpNet {
+        let len = match ip {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        };
+        IpNet::new(ip, len)
+            .expect("prefix lengths are checked when parsed")
+            .trunc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_order_like_addresses() {
+        let a = ip_key("45.148.10.7").unwrap();
+        let b = ip_key("45.148.10.200").unwrap();
+        let c = ip_key("2a06:4880::1").unwrap();
+        assert_eq!(a.bytes.len(), 16);
+        assert!(a.bytes < b.bytes && b.bytes < c.bytes);
+        assert!(ip_key("not an ip").is_none());
+        let net: IpNet = "45.148.10.0/24".parse().unwrap();
+        let range = in_any(&[net]);
+        let cond = range.get_array("$or").unwrap()[0]
+            .as_document()
+            .unwrap()
+            .get_document("ip_key")
+            .unwrap()
+            .clone();
+        match (cond.get("$gte"), cond.get("$lte")) {
+            (Some(bson::Bson::Binary(low)), Some(bson::Bson::Binary(high))) => {
+                assert!(low.bytes <= a.bytes && b.bytes <= high.bytes);
+                assert!(high.bytes < c.bytes);
+            }
+            other => panic!("expected binary bounds, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn prefix_grouping() {
+        let lens: PrefixLens = "24".parse().unwrap();
+        assert_eq!(lens, PrefixLens { v4: 24, v6: 64 });
+        assert_eq!(
+            lens.network("45.148.10.7".parse().unwrap()).to_string(),
+            "45.148.10.0/24"
+        );
+        assert_eq!(
+            lens.network("2a06:4880:1:2:3::9".parse().unwrap())
+                .to_string(),
+            "2a06:4880:1:2::/64"
+        );
+        assert!("33".parse::<PrefixLens>().is_err());
+        assert!("24,129".parse::<PrefixLens>().is_err());
+    }
+}
@@ -0,0 +1,410 @@
+// * config file loading: --config path, [profile.<name>] sections, LOGLOOK_* overrides and api_key_file
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+use tracing::info;
+
+use crate::timespec;
+
+const DEFAULT_PATH: &str = "~/.loglook/config.toml";
+const ENV_PREFIX: &str = "LOGLOOK_";
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Config {
+    #[serde(default)]
+    pub api_key: String,
+    // * file holding the api key; must not be readable by group or others
+    pub api_key_file: Option<PathBuf>,
+    pub db_uri: String,
+    pub db_name: String, // canonical name is loglook for prod, test_loglook for dev
+    // * log entries whose user agent contains any of these are not stored
+    #[serde(default = "default_ua_filters")]
+    pub ua_filters: Vec<String>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+}
+
+// added 11/1/2024
+// filter out uptimerobot entries
+pub(crate) fn default_ua_filters() -> Vec<String> {
+    vec!["uptimerobot".to_string()]
+}
+
+impl Config {
+    // * catch mistakes that would otherwise only show up at first use
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.api_key.trim().is_empty() {
+            bail!("api_key is empty; set api_key or api_key_file");
+        }
+        if !(self.db_uri.starts_with("mongodb://") || self.db_uri.starts_with("mongodb+srv://")) {
+            bail!("db_uri must start with mongodb:// or mongodb+srv://");
+        }
+        if self.db_name.trim().is_empty() {
+            bail!("db_name is empty");
+        }
+        if self.ua_filters.iter().any(|filter| filter.is_empty()) {
+            bail!("ua_filters must not contain an empty string, which would filter everything");
+        }
+        for spec in [&self.daemon.interval, &self.daemon.debounce]
+            .into_iter()
+            .flatten()
+        {
+            timespec::parse_duration(spec).context("Bad duration in [daemon] section")?;
+        }
+        if let Some(addr) = &self.daemon.metrics_addr {
+            addr.parse::<std::net::SocketAddr>()
+                .with_context(|| format!("Bad daemon.metrics_addr {addr:?}"))?;
+        }
+        Ok(())
+    }
+
+    // * describe what differs from other, without revealing secrets
+    pub fn changes(&self, other: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.api_key != other.api_key {
+            changes.push("api_key changed".to_string());
+        }
+        if self.db_uri != other.db_uri {
+            changes.push("db_uri changed".to_string());
+        }
+        if self.db_name != other.db_name {
+            changes.push(format!("db_name: {} -> {}", self.db_name, other.db_name));
+        }
+        if self.ua_filters != other.ua_filters {
+            changes.push(format!(
+                "ua_filters: {:?} -> {:?}",
+                self.ua_filters, other.ua_filters
+            ));
+        }
+        if self.retention != other.retention {
+            changes.push(format!(
+                "retention: {:?} -> {:?}",
+                self.retention, other.retention
+            ));
+        }
+        if self.daemon != other.daemon {
+            changes.push(format!("daemon: {:?} -> {:?}", self.daemon, other.daemon));
+        }
+        changes
+    }
+}
+
+// * [daemon] section of config file; cli flags take precedence
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct DaemonConfig {
+    // * time between read cycles, e.g. 30m
+    pub interval: Option<String>,
+    // * also read when the logfile changes
+    #[serde(default)]
+    pub watch: bool,
+    // * quiet time after a change before reading, e.g. 2s
+    pub debounce: Option<String>,
+    // * address to serve prometheus metrics on, e.g. 127.0.0.1:9898
+    pub metrics_addr: Option<String>,
+}
+
+// * [retention] section of config file; everything is kept unless configured
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct RetentionConfig {
+    pub logentry_days: Option<u32>,
+    // * enforce logentry_days with a TTL index on logentries.time
+    #[serde(default)]
+    pub ttl_index: bool,
+    // * remove hostdata for ips with no remaining log entries when pruning
+    #[serde(default)]
+    pub prune_orphans: bool,
+}
+
+impl RetentionConfig {
+    pub(crate) fn ttl_seconds(&self) -> Option<u64> {
+        match (self.ttl_index, self.logentry_days) {
+            (true, Some(days)) => Some(u64::from(days) * 24 * 60 * 60),
+            _ => None,
+        }
+    }
+}
+
+// * where to find the config; kept by the daemon so SIGHUP re-reads the same file and profile
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub profile: Option<String>,
+}
+
+// * overlay keys from over onto base, descending into tables present in both
+fn merge(base: &mut Table, over: Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(over_table)) => {
+                merge(base_table, over_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// * top level settings with the named profile's settings on top
+fn select_profile(mut table: Table, profile: Option<&str>) -> anyhow::Result<Table> {
+    let profiles = match table.remove("profile") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => bail!("profile must be a table of [profile.<name>] sections"),
+        None => Table::new(),
+    };
+    if let Some(name) = profile {
+        match profiles.get(name) {
+            Some(Value::Table(settings)) => merge(&mut table, settings.clone()),
+            _ => {
+                let known: Vec<&String> = profiles.keys().collect();
+                bail!("No [profile.{name}] in config; known profiles: {known:?}");
+            }
+        }
+    }
+    Ok(table)
+}
+
+// * settings in Config that are not strings; keep in step with the structs above
+const TYPED_SETTINGS: [(&str, SettingType); 5] = [
+    ("ua_filters", SettingType::List),
+    ("retention.logentry_days", SettingType::Integer),
+    ("retention.ttl_index", SettingType::Boolean),
+    ("retention.prune_orphans", SettingType::Boolean),
+    ("daemon.watch", SettingType::Boolean),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SettingType {
+    Boolean,
+    Integer,
+    // * comma separated, e.g. LOGLOOK_UA_FILTERS=uptimerobot,pingdom; empty for none
+    List,
+}
+
+// * env values are typed by the setting they set, so an all digit api key stays a string
+fn env_value(key: &str, value: &str) -> anyhow::Result<Value> {
+    let setting_type = TYPED_SETTINGS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, setting_type)| *setting_type);
+    let value = match setting_type {
+        None => Value::String(value.to_string()),
+        Some(SettingType::Boolean) => Value::Boolean(
+            value
+                .parse()
+                .with_context(|| format!("{key} must be true or false, not {value:?}"))?,
+        ),
+        Some(SettingType::Integer) => Value::Integer(
+            value
+                .parse()
+                .with_context(|| format!("{key} must be a number, not {value:?}"))?,
+        ),
+        Some(SettingType::List) => Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        ),
+    };
+    Ok(value)
+}
+
+// * LOGLOOK_DB_NAME sets db_name; a double underscore descends into a section,
+// * e.g. LOGLOOK_DAEMON__INTERVAL sets daemon.interval
+fn apply_env(
+    table: &mut Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<()> {
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        // * these choose the file and profile rather than settings in it
+        if key == "CONFIG" || key == "PROFILE" {
+            continue;
+        }
+        let path: Vec<String> = key.to_lowercase().split("__").map(String::from).collect();
+        let (last, sections) = path.split_last().expect("split yields at least one part");
+        let mut target = &mut *table;
+        for section in sections {
+            let entry = target
+                .entry(section.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            target = entry.as_table_mut().expect("entry was made a table");
+        }
+        let value = env_value(&path.join("."), &value).with_context(|| format!("In {name}"))?;
+        target.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+// * secrets file must be private to its owner, like ssh keys
+fn read_api_key_file(path: &Path) -> anyhow::Result<String> {
+    let path = PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).as_ref());
+    let mode = std::fs::metadata(&path)
+        .with_context(|| format!("Failed to read api_key_file {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "api_key_file {} has mode {:o}; it must not be accessible by group or others (chmod 600)",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    let key = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read api_key_file {}", path.display()))?;
+    Ok(key.trim().to_string())
+}
+
+fn parse_config(
+    text: &str,
+    profile: Option<&str>,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<Config> {
+    let table: Table = toml::from_str(text)?;
+    let mut table = select_profile(table, profile)?;
+    apply_env(&mut table, vars)?;
+    let mut config: Config = Value::Table(table).try_into()?;
+    if let Some(path) = &config.api_key_file {
+        config.api_key = read_api_key_file(path)?;
+    }
+    Ok(config)
+}
+
+pub fn read_config(source: &ConfigSource) -> anyhow::Result<Config> {
+    let path = match &source.path {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => shellexpand::tilde(DEFAULT_PATH).into_owned(),
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file: {path}"))?;
+    parse_config(&text, source.profile.as_deref(), std::env::vars())
+        .with_context(|| format!("Failed to read config file: {path}"))
+}
+
+// * re-read config for a running daemon; returns the new config only if it is valid and differs
+pub fn reload_config(current: &Config, source: &ConfigSource) -> anyhow::Result<Option<Config>> {
+    let new_config = read_config(source)?;
+    new_config.validate()?;
+    let changes = current.changes(&new_config);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    for change in changes {
+        info!(%change, "config reloaded");
+    }
+    Ok(Some(new_config))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio_test::assert_ok;
+
+    pub(crate) fn sample_config() -> Config {
+        Config {
+            api_key: "key".to_string(),
+            api_key_file: None,
+            db_uri: "mongodb://localhost:27017".to_string(),
+            db_name: "test_loglook".to_string(),
+            ua_filters: default_ua_filters(),
+            retention: RetentionConfig::default(),
+            daemon: DaemonConfig::default(),
+        }
+    }
+
+    const PROFILES: &str = r#"
+api_key = "key"
+db_uri = "mongodb://localhost:27017"
+db_name = "loglook"
+
+[daemon]
+interval = "30m"
+
+[profile.dev]
+db_name = "test_loglook"
+
+[profile.dev.daemon]
+watch = true
+"#;
+
+    #[test]
+    fn test_config_validate() {
+        let mut config = sample_config();
+        assert_ok!(config.validate());
+        config.db_uri = "localhost:27017".to_string();
+        assert!(config.validate().is_err());
+        config = sample_config();
+        config.daemon.interval = Some("soon".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_changes() {
+        let old = sample_config();
+        let mut new = sample_config();
+        assert!(old.changes(&new).is_empty());
+        new.api_key = "secret".to_string();
+        new.db_name = "loglook".to_string();
+        let changes = old.changes(&new);
+        assert_eq!(changes.len(), 2);
+        assert!(!changes.concat().contains("secret"));
+    }
+
+    #[test]
+    fn profile_overrides_top_level() {
+        let prod = parse_config(PROFILES, None, std::iter::empty()).unwrap();
+        assert_eq!(prod.db_name, "loglook");
+        assert!(!prod.daemon.watch);
+        let dev = parse_config(PROFILES, Some("dev"), std::iter::empty()).unwrap();
+        assert_eq!(dev.db_name, "test_loglook");
+        assert!(dev.daemon.watch);
+        assert_eq!(dev.daemon.interval.as_deref(), Some("30m"));
+        assert!(parse_config(PROFILES, Some("staging"), std::iter::empty()).is_err());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let vars = [
+            ("LOGLOOK_DB_NAME", "1234"),
+            ("LOGLOOK_API_KEY", "0042"),
+            ("LOGLOOK_UA_FILTERS", "uptimerobot, pingdom"),
+            ("LOGLOOK_DAEMON__WATCH", "true"),
+            ("LOGLOOK_RETENTION__LOGENTRY_DAYS", "90"),
+            ("LOGLOOK_PROFILE", "dev"),
+            ("HOME", "/root"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let config = parse_config(PROFILES, Some("dev"), vars.into_iter()).unwrap();
+        assert_eq!(config.db_name, "1234");
+        assert_eq!(config.api_key, "0042");
+        assert_eq!(config.ua_filters, ["uptimerobot", "pingdom"]);
+        assert!(config.daemon.watch);
+        assert_eq!(config.retention.logentry_days, Some(90));
+        let bad = [("LOGLOOK_DAEMON__WATCH", "yes")].map(|(k, v)| (k.to_string(), v.to_string()));
+        assert!(parse_config(PROFILES, None, bad.into_iter()).is_err());
+    }
+
+    #[test]
+    fn api_key_file_must_be_private() {
+        let path = std::env::temp_dir().join(format!("loglook-key-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read_api_key_file(&path).is_err());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let key = read_api_key_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(key.unwrap(), "secret");
+    }
+}
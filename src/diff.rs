@@ -0,0 +1,321 @@
+// * period-over-period comparison: what appeared, disappeared or changed between two ranges
+use chrono::Utc;
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::log_entries::LogEntry;
+use crate::output::{self, OutputFormat};
+use crate::query::{self, DateRange};
+use crate::rollup::KeyCount;
+use crate::stats::{all_counts, all_counts_by_host, path};
+use crate::timespec::RangeArgs;
+use crate::Config;
+
+// * a key's request count in each range; 0 where it was not seen
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub key: String,
+    pub a: i64,
+    pub b: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffSection {
+    pub name: &'static str,
+    // * each list holds at most --top keys, the largest first
+    pub appeared: Vec<Change>,
+    pub disappeared: Vec<Change>,
+    pub changed: Vec<Change>,
+    // * sizes before --top was applied
+    pub appeared_total: usize,
+    pub disappeared_total: usize,
+    pub changed_total: usize,
+    pub unchanged_total: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub a_start: String,
+    pub a_end: String,
+    pub b_start: String,
+    pub b_end: String,
+    pub sections: Vec<DiffSection>,
+    // * dimensions that cannot be compared for lack of data
+    pub unavailable: Vec<&'static str>,
+}
+
+// * no ASN is looked up or stored; orgs are the nearest substitute
+const UNAVAILABLE: [&str; 1] = ["asns"];
+
+// * ips are counted the way the rest of loglook finds them; the others by pipeline stages
+enum Dimension {
+    Ips,
+    Stages(Vec<Document>),
+}
+
+// * what is compared; there is no ASN data, so organizations stand in for ASNs
+fn dimensions() -> Vec<(&'static str, Dimension)> {
+    vec![
+        ("ips", Dimension::Ips),
+        (
+            "countries",
+            Dimension::Stages(all_counts_by_host("country_name")),
+        ),
+        (
+            "orgs",
+            Dimension::Stages(all_counts_by_host("organization")),
+        ),
+        ("paths", Dimension::Stages(all_counts(path()))),
+    ]
+}
+
+fn keep_top(mut changes: Vec<Change>, size: impl Fn(&Change) -> i64, top: usize) -> Vec<Change> {
+    changes.sort_by(|x, y| size(y).cmp(&size(x)).then_with(|| x.key.cmp(&y.key)));
+    changes.truncate(top);
+    changes
+}
+
+pub fn compare(
+    name: &'static str,
+    a: &HashMap<String, i64>,
+    b: &HashMap<String, i64>,
+    top: usize,
+) -> DiffSection {
+    let mut appeared = vec![];
+    let mut disappeared = vec![];
+    let mut changed = vec![];
+    let mut unchanged_total = 0;
+    for (key, &b_count) in b {
+        match a.get(key) {
+            None => appeared.push(Change {
+                key: key.clone(),
+                a: 0,
+                b: b_count,
+            }),
+            Some(&a_count) if a_count != b_count => changed.push(Change {
+                key: key.clone(),
+                a: a_count,
+                b: b_count,
+            }),
+            Some(_) => unchanged_total += 1,
+        }
+    }
+    for (key, &a_count) in a {
+        if !b.contains_key(key) {
+            disappeared.push(Change {
+                key: key.clone(),
+                a: a_count,
+                b: 0,
+            });
+        }
+    }
+    DiffSection {
+        name,
+        appeared_total: appeared.len(),
+        disappeared_total: disappeared.len(),
+        changed_total: changed.len(),
+        unchanged_total,
+        appeared: keep_top(appeared, |c| c.b, top),
+        disappeared: keep_top(disappeared, |c| c.a, top),
+        changed: keep_top(changed, |c| (c.b - c.a).abs(), top),
+    }
+}
+
+// * one aggregation per dimension, streamed, so no single result document grows too large
+async fn counts(
+    logents_coll: &mongodb::Collection<LogEntry>,
+    date_range: &DateRange,
+    dimension: &Dimension,
+) -> anyhow::Result<HashMap<String, i64>> {
+    let stages = match dimension {
+        Dimension::Ips => return query::count_ips_in_daterange(logents_coll, date_range).await,
+        Dimension::Stages(stages) => stages,
+    };
+    let mut pipeline =
+        vec![doc! {"$match": {"time": {"$gte": date_range.start, "$lt": date_range.end}}}];
+    pipeline.extend(stages.iter().cloned());
+    let mut curs = logents_coll.aggregate(pipeline, None).await?;
+    let mut counts = HashMap::new();
+    while let Some(doc) = curs.try_next().await? {
+        let kc: KeyCount = bson::from_document(doc)?;
+        counts.insert(kc.key, kc.count);
+    }
+    Ok(counts)
+}
+
+impl DiffReport {
+    fn print(&self) {
+        println!(
+            "{}: {} to {}",
+            style("A").red(),
+            style(&self.a_start).yellow(),
+            style(&self.a_end).yellow()
+        );
+        println!(
+            "{}: {} to {}",
+            style("B").red(),
+            style(&self.b_start).yellow(),
+            style(&self.b_end).yellow()
+        );
+        for section in &self.sections {
+            println!("{}", style(section.name).red());
+            println!("  appeared ({})", section.appeared_total);
+            for c in &section.appeared {
+                println!("  {:>8}  {}", format!("+{}", c.b), c.key);
+            }
+            println!("  disappeared ({})", section.disappeared_total);
+            for c in &section.disappeared {
+                println!("  {:>8}  {}", format!("-{}", c.a), c.key);
+            }
+            println!(
+                "  changed ({} of {} in both)",
+                section.changed_total,
+                section.changed_total + section.unchanged_total
+            );
+            for c in &section.changed {
+                println!(
+                    "  {:>8}  {} -> {}  {}",
+                    format!("{:+}", c.b - c.a),
+                    c.a,
+                    c.b,
+                    c.key
+                );
+            }
+        }
+        for name in &self.unavailable {
+            println!("{}", style(name).red());
+            println!("  not available: no ASN data is stored; see orgs");
+        }
+    }
+
+    // * long form for csv: one section,change,key,a,b row per key
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut rows = vec![];
+        for section in &self.sections {
+            for (kind, changes) in [
+                ("appeared", &section.appeared),
+                ("disappeared", &section.disappeared),
+                ("changed", &section.changed),
+            ] {
+                for c in changes {
+                    rows.push(vec![
+                        section.name.to_string(),
+                        kind.to_string(),
+                        c.key.clone(),
+                        c.a.to_string(),
+                        c.b.to_string(),
+                    ]);
+                }
+            }
+        }
+        for name in &self.unavailable {
+            rows.push(vec![
+                name.to_string(),
+                "unavailable".to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+        rows
+    }
+}
+
+pub async fn diff(
+    a: &str,
+    b: &str,
+    range: &RangeArgs,
+    top: usize,
+    format: OutputFormat,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let (a_start, a_end) = range.resolve_spec(a, now)?;
+    let (b_start, b_end) = range.resolve_spec(b, now)?;
+    let a_range = DateRange {
+        start: a_start.into(),
+        end: a_end.into(),
+    };
+    let b_range = DateRange {
+        start: b_start.into(),
+        end: b_end.into(),
+    };
+    let (_, _, logents_coll) = crate::setup_db(config).await?;
+    let mut sections = vec![];
+    for (name, dimension) in dimensions() {
+        let a_counts = counts(&logents_coll, &a_range, &dimension).await?;
+        let b_counts = counts(&logents_coll, &b_range, &dimension).await?;
+        sections.push(compare(name, &a_counts, &b_counts, top));
+    }
+    let report = DiffReport {
+        a_start: a_start.to_rfc3339(),
+        a_end: a_end.to_rfc3339(),
+        b_start: b_start.to_rfc3339(),
+        b_end: b_end.to_rfc3339(),
+        sections,
+        unavailable: UNAVAILABLE.to_vec(),
+    };
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Table => report.print(),
+        OutputFormat::Json => output::write_json(&mut out, &[report])?,
+        OutputFormat::Ndjson => output::write_ndjson(&mut out, &[report])?,
+        OutputFormat::Csv => output::write_csv(
+            &mut out,
+            &["section", "change", "key", "a", "b"],
+            report.rows(),
+        )?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(pairs: &[(&str, i64)]) -> HashMap<String, i64> {
+        pairs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn compare_sorts_changes() {
+        let a = counts(&[
+            ("1.1.1.1", 5),
+            ("2.2.2.2", 3),
+            ("3.3.3.3", 7),
+            ("4.4.4.4", 1),
+        ]);
+        let b = counts(&[
+            ("2.2.2.2", 3),
+            ("3.3.3.3", 1),
+            ("4.4.4.4", 9),
+            ("5.5.5.5", 2),
+        ]);
+        let section = compare("ips", &a, &b, 10);
+        assert_eq!(
+            section.appeared,
+            vec![Change {
+                key: "5.5.5.5".to_string(),
+                a: 0,
+                b: 2
+            }]
+        );
+        assert_eq!(section.disappeared[0].key, "1.1.1.1");
+        let changed: Vec<&str> = section.changed.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(changed, vec!["4.4.4.4", "3.3.3.3"]);
+        assert_eq!(section.unchanged_total, 1);
+    }
+
+    #[test]
+    fn compare_keeps_top() {
+        let a = HashMap::new();
+        let b = counts(&[("/a", 1), ("/b", 5), ("/c", 3)]);
+        let section = compare("paths", &a, &b, 2);
+        assert_eq!(section.appeared_total, 3);
+        let keys: Vec<&str> = section.appeared.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, vec!["/b", "/c"]);
+    }
+}
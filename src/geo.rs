@@ -2,9 +2,13 @@
 use reqwest;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::{fmt, sync::Arc};
+use std::{fmt, sync::Arc, time::Instant};
 use tokio::sync::mpsc;
 
+use tracing::{instrument, warn};
+
+use crate::metrics::METRICS;
+
 #[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Geodata {
@@ -43,38 +47,68 @@ impl fmt::Display for Geodata {
 
 // * send error message encapsulated in a Geodata struct
 async fn send_error(tx: mpsc::Sender<Geodata>, ip: &str, msg: &str) {
+    warn!("{msg}");
     let mut geod = Geodata::new(ip);
     // geod.ip = format!("{}", ip).to_string();
     geod.city = format!("Error in geodata lookup: {}", msg).to_string();
     tx.send(geod).await.expect("shd send geod error");
 }
 
-pub async fn geo_lkup(ip: &str, tx: mpsc::Sender<Geodata>, api_key: Arc<String>) {
+pub(crate) enum GeoError {
+    Request(reqwest::Error),
+    Status(reqwest::StatusCode),
+    Decode(serde_json::Error),
+}
+
+impl GeoError {
+    // * label for the lookup_failures metric
+    fn reason(&self) -> &'static str {
+        match self {
+            GeoError::Request(_) => "request",
+            GeoError::Status(_) => "status",
+            GeoError::Decode(_) => "decode",
+        }
+    }
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeoError::Request(e) => write!(f, "request failed: {e}"),
+            GeoError::Status(status) => write!(f, "service returned {status}"),
+            GeoError::Decode(e) => write!(f, "error decoding json {e}"),
+        }
+    }
+}
+
+pub(crate) async fn fetch_geodata(ip: &str, api_key: &str) -> Result<Geodata, GeoError> {
     let uri = format!("https://api.ipgeolocation.io/ipgeo?apiKey={api_key}&ip={ip}");
-    // unresolved unwrap in next line caused crash of system on 12/9/2024
+    let started = Instant::now();
     let res = reqwest::get(uri).await;
-    match res {
-        Ok(res) => {
-            if res.status() == 200 {
-                let text = res.text().await.unwrap();
-                match serde_json::from_str(&text) as Result<Geodata, serde_json::Error> {
-                    Ok(geodata) => {
-                        tx.send(geodata).await.expect("geodata send shd work");
-                    }
-                    Err(e) => {
-                        let msg = format!("error decoding json {}", e);
-                        send_error(tx, ip, &msg).await;
-                    }
-                };
-            } else {
-                let msg = format!("error acquiring geodata for IP {:?}", ip);
-                send_error(tx, ip, &msg).await;
-            }
+    METRICS.geo_latency.observe(started.elapsed().as_secs_f64());
+    // * drop the url from errors so the api key does not end up in logs
+    let res = res.map_err(|e| GeoError::Request(e.without_url()))?;
+    if res.status() != 200 {
+        return Err(GeoError::Status(res.status()));
+    }
+    // unresolved unwrap here caused crash of system on 12/9/2024
+    let text = res
+        .text()
+        .await
+        .map_err(|e| GeoError::Request(e.without_url()))?;
+    serde_json::from_str(&text).map_err(GeoError::Decode)
+}
+
+#[instrument(skip(tx, api_key))]
+pub async fn geo_lkup(ip: &str, tx: mpsc::Sender<Geodata>, api_key: Arc<String>) {
+    match fetch_geodata(ip, &api_key).await {
+        Ok(geodata) => {
+            tx.send(geodata).await.expect("geodata send shd work");
         }
         Err(e) => {
+            METRICS.lookup_failed("geo", e.reason());
             let msg = format!("error acquiring geodata for IP {:?}: {}", ip, e);
             send_error(tx, ip, &msg).await;
-            return;
         }
     }
 }
@@ -82,7 +116,7 @@ pub async fn geo_lkup(ip: &str, tx: mpsc::Sender<Geodata>, api_key: Arc<String>)
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::read_config;
+    use crate::{read_config, ConfigSource};
     // use tokio_test::assert_err;
 
     macro_rules! aw {
@@ -93,7 +127,7 @@ mod tests {
 
     #[test]
     fn geo_lkup_bad_ip() {
-        let conf = read_config().unwrap();
+        let conf = read_config(&ConfigSource::default()).unwrap();
         let api_key = conf.api_key;
         let key = Arc::new(api_key);
         let (tx, _rx) = mpsc::channel(32);
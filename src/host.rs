@@ -0,0 +1,285 @@
+// * everything known about one ip, over all stored entries
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::output::{self, HostRecord, OutputFormat};
+use crate::rollup::{print_key_counts, KeyCount};
+use crate::stats::{path, status_class, top_n};
+use crate::timeline::{bucket_start, BucketCount, BucketSize, Timeline};
+use crate::{Config, HostDataColl};
+
+// * cap on neighbours listed per kind
+const MAX_NEIGHBOURS: i64 = 50;
+
+fn totals() -> Document {
+    doc! {"$group": doc! {
+        "_id": null,
+        "requests": doc! {"$sum": 1},
+        "bytes": doc! {"$sum": "$nbytes"},
+        "first_seen": doc! {"$min": "$time"},
+        "last_seen": doc! {"$max": "$time"},
+    }}
+}
+
+fn facets(top: usize, size: BucketSize) -> Document {
+    doc! {
+        "status": top_n(status_class(), 10),
+        "paths": top_n(path(), top),
+        "user_agents": top_n("$ua", top),
+        "activity": [
+            doc! {"$group": doc! {"_id": bucket_start(size, chrono_tz::UTC), "count": doc! {"$sum": 1}}},
+            doc! {"$project": doc! {"_id": 0, "bucket": "$_id", "series": "requests", "count": 1}},
+        ],
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Totals {
+    requests: i64,
+    bytes: i64,
+    first_seen: bson::DateTime,
+    last_seen: bson::DateTime,
+}
+
+#[derive(Debug, Deserialize)]
+struct Facets {
+    status: Vec<KeyCount>,
+    paths: Vec<KeyCount>,
+    user_agents: Vec<KeyCount>,
+    activity: Vec<BucketCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostReport {
+    pub ip: String,
+    // * absent if the ip has not been looked up yet
+    pub hostdata: Option<HostRecord>,
+    pub first_seen: Option<String>,
+    pub last_seen: Option<String>,
+    pub requests: i64,
+    pub bytes: i64,
+    pub status: BTreeMap<String, i64>,
+    pub top_paths: Vec<KeyCount>,
+    pub user_agents: Vec<KeyCount>,
+    pub activity: Option<Timeline>,
+    // * other known ips in the same /24 (IPv4 only)
+    pub same_subnet: Vec<String>,
+    // * other known ips with the same geo organization; no ASN is stored
+    pub same_organization: Vec<String>,
+}
+
+// * a day-by-day chart for long histories, hour-by-hour for short ones
+fn activity_bucket(first: DateTime<Utc>, last: DateTime<Utc>) -> BucketSize {
+    if last - first > TimeDelta::days(3) {
+        BucketSize::Day
+    } else {
+        BucketSize::Hour
+    }
+}
+
+// * regex matching other addresses in ip's /24
+fn subnet_pattern(ip: &IpAddr) -> Option<String> {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            Some(format!(r"^{a}\.{b}\.{c}\.\d+$"))
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+async fn neighbours(
+    hostdata_coll: &HostDataColl,
+    filter: Document,
+    ip: &str,
+) -> anyhow::Result<Vec<String>> {
+    let options = FindOptions::builder()
+        .sort(doc! {"ip": 1})
+        .limit(MAX_NEIGHBOURS)
+        .build();
+    let filter = doc! {"$and": [filter, doc! {"ip": doc! {"$ne": ip}}]};
+    let hosts: Vec<_> = hostdata_coll
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(hosts.into_iter().map(|hd| hd.ip).collect())
+}
+
+impl HostReport {
+    fn print(&self) {
+        match &self.hostdata {
+            Some(hd) => {
+                println!("{}: {}", style("IP").bold().red(), style(&hd.ip).green());
+                println!("Loc: {}, {}, {}", hd.city, hd.state_prov, hd.country);
+                println!("ISP: {}", hd.isp);
+                println!("Org: {}", hd.organization);
+                for record in &hd.ptr_records {
+                    println!("{}: {}", style("host").red(), style(record).green());
+                }
+            }
+            None => println!(
+                "{}: {} (not looked up yet)",
+                style("IP").bold().red(),
+                style(&self.ip).green()
+            ),
+        }
+        if let (Some(first), Some(last)) = (&self.first_seen, &self.last_seen) {
+            println!("{}: {}", style("First seen").red(), first);
+            println!("{}: {}", style("Last seen").red(), last);
+        }
+        println!("{}: {}", style("Requests").red(), self.requests);
+        println!("{}: {}", style("Bytes").red(), self.bytes);
+        for (class, count) in &self.status {
+            println!("{}: {}", style(class).red(), count);
+        }
+        print_key_counts("Top paths", &self.top_paths);
+        print_key_counts("User agents", &self.user_agents);
+        if let Some(activity) = &self.activity {
+            println!("{}", style("Activity").red());
+            activity.print_sparklines();
+        }
+        println!("{}", style("Same /24").red());
+        for ip in &self.same_subnet {
+            println!("  {ip}");
+        }
+        println!("{}", style("Same organization").red());
+        for ip in &self.same_organization {
+            println!("  {ip}");
+        }
+    }
+}
+
+pub async fn host(
+    ip: &str,
+    top: usize,
+    format: OutputFormat,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let addr: IpAddr = ip
+        .parse()
+        .map_err(|_| anyhow!("{ip:?} is not an IP address"))?;
+    // * stored ips are in canonical form, e.g. 2001:db8::1 for 2001:DB8:0::1
+    let ip = addr.to_string();
+    let ip = ip.as_str();
+    let (_, hostdata_coll, logents_coll) = crate::setup_db(config).await?;
+    let hostdata = hostdata_coll.find_one(doc! {"ip": ip}, None).await?;
+
+    // * totals first; their span picks the bucket size of the activity chart
+    let totals_pipeline = vec![doc! {"$match": doc! {"ip": ip}}, totals()];
+    let totals: Option<Totals> = logents_coll
+        .aggregate(totals_pipeline, None)
+        .await?
+        .with_type::<Totals>()
+        .try_next()
+        .await?;
+    if hostdata.is_none() && totals.is_none() {
+        bail!("Nothing known about {ip}");
+    }
+
+    let mut report = HostReport {
+        ip: ip.to_string(),
+        hostdata: hostdata
+            .as_ref()
+            .map(|hd| HostRecord::new(ip, Some(hd), None)),
+        first_seen: None,
+        last_seen: None,
+        requests: 0,
+        bytes: 0,
+        status: BTreeMap::new(),
+        top_paths: vec![],
+        user_agents: vec![],
+        activity: None,
+        same_subnet: vec![],
+        same_organization: vec![],
+    };
+    if let Some(totals) = totals {
+        let (first, last) = (totals.first_seen.to_chrono(), totals.last_seen.to_chrono());
+        report.first_seen = Some(first.to_rfc3339());
+        report.last_seen = Some(last.to_rfc3339());
+        report.requests = totals.requests;
+        report.bytes = totals.bytes;
+        let size = activity_bucket(first, last);
+        let pipeline = vec![
+            doc! {"$match": doc! {"ip": ip}},
+            doc! {"$facet": facets(top, size)},
+        ];
+        let mut docs: Vec<Document> = logents_coll
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        let facets: Facets = bson::from_document(
+            docs.pop()
+                .ok_or(anyhow!("host aggregation returned nothing"))?,
+        )?;
+        report.status = facets
+            .status
+            .into_iter()
+            .map(|kc| (kc.key, kc.count))
+            .collect();
+        report.top_paths = facets.paths;
+        report.user_agents = facets.user_agents;
+        report.activity = Some(Timeline::new(
+            first,
+            last + TimeDelta::milliseconds(1),
+            size,
+            chrono_tz::UTC,
+            facets.activity,
+        )?);
+    }
+    if let Some(pattern) = subnet_pattern(&addr) {
+        let filter = doc! {"ip": bson::Regex { pattern, options: String::new() }};
+        report.same_subnet = neighbours(&hostdata_coll, filter, ip).await?;
+    }
+    if let Some(hd) = &hostdata {
+        if !hd.geodata.organization.is_empty() {
+            let filter = doc! {"geodata.organization": &hd.geodata.organization};
+            report.same_organization = neighbours(&hostdata_coll, filter, ip).await?;
+        }
+    }
+
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Table => report.print(),
+        OutputFormat::Json => output::write_json(&mut out, &[report])?,
+        OutputFormat::Ndjson => output::write_ndjson(&mut out, &[report])?,
+        OutputFormat::Csv => bail!("A host report does not fit in csv; use --format json"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_is_ipv4_slash_24() {
+        let pattern = subnet_pattern(&"203.0.113.7".parse().unwrap()).unwrap();
+        let re = regex::Regex::new(&pattern).unwrap();
+        assert!(re.is_match("203.0.113.200"));
+        assert!(!re.is_match("203.0.11.3"));
+        assert!(!re.is_match("203.0.1130.1"));
+        assert!(subnet_pattern(&"2001:db8::1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn activity_bucket_follows_span() {
+        let first: DateTime<Utc> = "2024-12-09T00:00:00Z".parse().unwrap();
+        assert_eq!(
+            activity_bucket(first, first + TimeDelta::hours(5)),
+            BucketSize::Hour
+        );
+        assert_eq!(
+            activity_bucket(first, first + TimeDelta::days(30)),
+            BucketSize::Day
+        );
+    }
+}
@@ -0,0 +1,211 @@
+// * advisory single-instance lock: a lease document per database and logfile, renewed while held
+use anyhow::anyhow;
+use mongodb::bson::doc;
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::{checkpoint, Config};
+
+// * a holder that stops renewing loses the lease after LEASE_TTL
+const LEASE_TTL: Duration = Duration::from_secs(300);
+const RENEW_EVERY: Duration = Duration::from_secs(60);
+// * after a failed renewal, e.g. during a failover, retry sooner
+const RETRY_EVERY: Duration = Duration::from_secs(10);
+// * give up this long before the lease would expire, so another instance cannot overlap
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LeaseDoc {
+    #[serde(rename = "_id")]
+    id: String,
+    holder: String,
+    expires_at: bson::DateTime,
+}
+
+pub struct Lease {
+    coll: Collection<LeaseDoc>,
+    id: String,
+    holder: String,
+}
+
+fn lease_id(db_name: &str, path: &Path) -> String {
+    format!("{db_name}:{}", checkpoint::path_key(path))
+}
+
+// * host and pid, so the refusal message says where the other instance runs
+fn holder() -> String {
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string());
+    format!("{host}:{}", std::process::id())
+}
+
+fn expires_at() -> bson::DateTime {
+    (chrono::Utc::now() + LEASE_TTL).into()
+}
+
+// * matches the lease only if it is ours or has expired
+fn claimable(id: &str, holder: &str) -> bson::Document {
+    doc! {
+        "_id": id,
+        "$or": [
+            doc! {"holder": holder},
+            doc! {"expires_at": doc! {"$lt": bson::DateTime::now()}},
+        ]
+    }
+}
+
+// * whether a lease last renewed this long ago is too close to expiry to keep retrying
+fn near_expiry(since_renewed: Duration) -> bool {
+    since_renewed + RETRY_EVERY + EXPIRY_MARGIN >= LEASE_TTL
+}
+
+fn is_duplicate(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(we)) => we.code == DUPLICATE_KEY,
+        ErrorKind::Command(ce) => ce.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+impl Lease {
+    // * fails with the current holder if another live instance has the lease
+    pub async fn acquire(config: &Config, path: &Path) -> anyhow::Result<Lease> {
+        let client = Client::with_uri_str(&config.db_uri).await?;
+        let coll: Collection<LeaseDoc> = client.database(&config.db_name).collection("locks");
+        // * lets mongo clean up leases abandoned by crashed instances
+        let ttl_index = IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        coll.create_index(ttl_index, None).await?;
+
+        let lease = Lease {
+            coll,
+            id: lease_id(&config.db_name, path),
+            holder: holder(),
+        };
+        let update = doc! {"$set": doc! {"holder": &lease.holder, "expires_at": expires_at()}};
+        let options = UpdateOptions::builder().upsert(true).build();
+        match lease
+            .coll
+            .update_one(claimable(&lease.id, &lease.holder), update, options)
+            .await
+        {
+            Ok(_) => {
+                debug!("Acquired lease {} as {}", lease.id, lease.holder);
+                Ok(lease)
+            }
+            Err(e) if is_duplicate(&e) => {
+                let current = lease.coll.find_one(doc! {"_id": &lease.id}, None).await?;
+                Err(match current {
+                    Some(current) => anyhow!(
+                        "Another loglook instance ({}) is already reading {} into {}; its lease expires at {}",
+                        current.holder,
+                        path.display(),
+                        config.db_name,
+                        current.expires_at
+                    ),
+                    None => anyhow!("Lost race for lease {}; try again", lease.id),
+                })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // * false if another instance has taken the lease over
+    pub async fn renew(&self) -> anyhow::Result<bool> {
+        let result = self
+            .coll
+            .update_one(
+                doc! {"_id": &self.id, "holder": &self.holder},
+                doc! {"$set": doc! {"expires_at": expires_at()}},
+                None,
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn release(&self) -> anyhow::Result<()> {
+        self.coll
+            .delete_one(doc! {"_id": &self.id, "holder": &self.holder}, None)
+            .await?;
+        Ok(())
+    }
+
+    // * renew until cancelled, retrying failed renewals while the lease is still valid;
+    // * losing the lease cancels the token to stop ingesting and returns the reason
+    pub async fn hold(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+        let mut last_renewed = Instant::now();
+        let mut wait = RENEW_EVERY;
+        loop {
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = cancel.cancelled() => return Ok(()),
+            }
+            let lost = match self.renew().await {
+                Ok(true) => {
+                    last_renewed = Instant::now();
+                    wait = RENEW_EVERY;
+                    continue;
+                }
+                Ok(false) => anyhow!("Lease {} was taken over by another instance", self.id),
+                Err(e) if !near_expiry(last_renewed.elapsed()) => {
+                    warn!("Lease renewal failed, retrying: {e:#}");
+                    wait = RETRY_EVERY;
+                    continue;
+                }
+                Err(e) => e.context(format!(
+                    "Lease {} could not be renewed before expiry",
+                    self.id
+                )),
+            };
+            error!("Lost lease, shutting down: {lost:#}");
+            cancel.cancel();
+            return Err(lost);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_keyed_on_db_and_path() {
+        let id = lease_id("loglook", Path::new("/nonexistent/access.log"));
+        assert_eq!(id, "loglook:/nonexistent/access.log");
+        assert_ne!(id, lease_id("other", Path::new("/nonexistent/access.log")));
+    }
+
+    #[test]
+    fn renewal_retries_until_near_expiry() {
+        assert!(!near_expiry(RENEW_EVERY));
+        assert!(!near_expiry(RENEW_EVERY * 3));
+        assert!(near_expiry(LEASE_TTL - EXPIRY_MARGIN));
+    }
+
+    #[test]
+    fn claimable_when_ours_or_expired() {
+        let filter = claimable("loglook:/var/log/a.log", "host:1");
+        let or = filter.get_array("$or").unwrap();
+        assert_eq!(or.len(), 2);
+        assert_eq!(
+            or[0].as_document().unwrap().get_str("holder").unwrap(),
+            "host:1"
+        );
+        assert!(or[1].as_document().unwrap().contains_key("expires_at"));
+    }
+}
@@ -2,48 +2,66 @@ use anyhow::{anyhow, bail, Context};
 use console::style;
 use futures::StreamExt;
 use mongodb::bson::doc;
-use mongodb::options::IndexOptions;
-use query::DateRange;
+use mongodb::options::{FindOptions, IndexOptions};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Lines};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-use config_file::FromConfigFile;
 use mongodb::{Client, Collection, IndexModel};
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::task::JoinSet;
-
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn, Instrument};
+
+pub mod check;
+pub mod checkpoint;
+pub mod cidr;
+pub mod config;
+pub mod diff;
 pub mod geo;
+pub mod host;
+pub mod lease;
 pub mod lkup;
 pub mod log_entries;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod output;
+pub mod prune;
 pub mod query;
-
+pub mod rollup;
+pub mod stats;
+pub mod systemd;
+pub mod timeline;
+pub mod timespec;
+pub mod transfer;
+pub mod watch;
+
+pub use config::{read_config, reload_config, Config, ConfigSource, DaemonConfig, RetentionConfig};
 use log_entries::LogEntry;
 
 use crate::lkup::RevLookupData;
+use crate::metrics::METRICS;
+use crate::output::{EntryRecord, HostRecord, OutputFormat};
 
 type Logdate = chrono::DateTime<chrono::Utc>;
 type LogEntryColl = Collection<LogEntry>;
 type HostDataColl = Collection<HostData>;
 
-#[derive(Deserialize)]
-pub struct Config {
-    pub api_key: String,
-    pub db_uri: String,
-    pub db_name: String, // canonical name is loglook for prod, test_loglook for dev
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HostData {
     pub ip: String,
+    // * binary form of ip for range queries; absent in databases not yet migrated to v3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_key: Option<bson::Binary>,
     pub geodata: geo::Geodata,
     pub ptr_records: Vec<String>,
 }
@@ -72,22 +90,38 @@ pub struct Counts {
     pub n_skipped_les: usize,
 }
 
-pub fn read_config() -> anyhow::Result<Config> {
-    let path = shellexpand::tilde("~/.loglook/config.toml");
-    let config = Config::from_config_file(path.as_ref())
-        .with_context(|| format!("Failed to read config file: {path}"))?;
-    Ok(config)
+// * a complete line from the logfile and the file offset just past it
+struct LogLine {
+    text: String,
+    end: u64,
 }
 
-fn read_lines(path: &PathBuf) -> anyhow::Result<io::Lines<BufReader<File>>> {
+// * read complete lines starting at offset; a partial last line is left for the next read
+fn read_lines(path: &PathBuf, offset: u64) -> anyhow::Result<Vec<LogLine>> {
     let path_string = path
         .to_str()
         .ok_or(anyhow!("Failed to convert path to string"))?;
-    let file = File::open(path).with_context(|| format!("Failed to open file {path_string}"))?;
-    Ok(io::BufReader::new(file).lines())
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file {path_string}"))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut lines = Vec::new();
+    let mut end = offset;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let n = reader.read_until(b'\n', &mut buf)?;
+        if n == 0 || buf.last() != Some(&b'\n') {
+            break;
+        }
+        end += n as u64;
+        let text = String::from_utf8_lossy(&buf).trim_end().to_string();
+        lines.push(LogLine { text, end });
+    }
+    Ok(lines)
 }
 
-fn logents_to_ips_set<'ips>(logentries: &[LogEntry]) -> HashSet<String> {
+fn logents_to_ips_set(logentries: &[LogEntry]) -> HashSet<String> {
     let mut ips = HashSet::new();
     for logentry in logentries {
         ips.insert(logentry.ip.clone());
@@ -95,29 +129,44 @@ fn logents_to_ips_set<'ips>(logentries: &[LogEntry]) -> HashSet<String> {
     ips
 }
 
-fn make_logentries(lines: Lines<BufReader<File>>) -> Vec<LogEntry> {
-    let maybe_logentries: Vec<anyhow::Result<LogEntry>> = lines
-        .map(|line| log_entries::LogEntry::try_from(&line.expect("log line should be readable")))
-        .collect();
-    // * deal with errors (poss bad lines in log) here by displaying on stderr
-    let mut logentries: Vec<LogEntry> = Vec::new();
-    for maybe_logentry in maybe_logentries.into_iter() {
-        match maybe_logentry {
+// * parsed entries paired with the offset just past their line
+// * stops early if cancelled; also returns the offset up to which lines were examined
+fn make_logentries(
+    lines: Vec<LogLine>,
+    start: u64,
+    ua_filters: &[String],
+    cancel: &CancellationToken,
+) -> (Vec<(LogEntry, u64)>, u64) {
+    let mut logentries = Vec::new();
+    let mut parsed_to = start;
+    for line in lines {
+        if cancel.is_cancelled() {
+            break;
+        }
+        METRICS.lines_parsed.inc();
+        // * deal with errors (poss bad lines in log) here by displaying on stderr
+        match log_entries::LogEntry::try_from(&line.text) {
             Ok(logentry) => {
-                // added 11/1/2024
-                // filter out uptimerobot entries
-                if !logentry.ua.contains("uptimerobot") {
-                    logentries.push(logentry)
+                if !ua_filters.iter().any(|filter| logentry.ua.contains(filter)) {
+                    logentries.push((logentry, line.end))
                 }
             }
-            Err(e) => eprintln!("Log read error: {}", e),
+            Err(e) => {
+                METRICS.parse_errors.inc();
+                warn!("Log read error: {}", e)
+            }
         }
+        parsed_to = line.end;
     }
 
-    logentries
+    (logentries, parsed_to)
 }
 
 fn progress_bar_setup(n_pb_items: u64) -> (ProgressBar, ProgressBar) {
+    // * no bars under a supervisor or when output is piped
+    if !logging::is_interactive() {
+        return (ProgressBar::hidden(), ProgressBar::hidden());
+    }
     let m = MultiProgress::new();
     let sty = ProgressStyle::with_template(
         "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
@@ -133,11 +182,16 @@ fn progress_bar_setup(n_pb_items: u64) -> (ProgressBar, ProgressBar) {
     (pb_rdns, pb_geo)
 }
 
-async fn setup_db(
+pub(crate) async fn setup_db(
     config: &Config,
 ) -> anyhow::Result<(mongodb::Database, HostDataColl, LogEntryColl)> {
     let client = Client::with_uri_str(&config.db_uri).await?;
     let db = client.database(&config.db_name);
+    // * a database created now needs no migrations, so it starts at the latest schema version
+    let collections = db.list_collection_names(None).await?;
+    let fresh = !collections
+        .iter()
+        .any(|name| name == "logentries" || name == "hostdata");
     let host_data_coll: HostDataColl = db.collection("hostdata");
     let hd_options = IndexOptions::builder().unique(true).build();
     let hd_index_model = IndexModel::builder()
@@ -153,6 +207,10 @@ async fn setup_db(
     host_data_coll
         .create_index(hd_country_index_model, None)
         .await?;
+    let hd_ip_key_index_model = IndexModel::builder().keys(doc! {"ip_key": 1}).build();
+    host_data_coll
+        .create_index(hd_ip_key_index_model, None)
+        .await?;
     // * Indices on LogEntry collection
     // * Need several; first is compound on ip and time
     let logents_coll: LogEntryColl = db.collection("logentries");
@@ -164,12 +222,40 @@ async fn setup_db(
         .build();
     #[allow(unused_variables)]
     let le_index = logents_coll.create_index(le_index_model, None).await?;
+    // * ip_key serves CIDR searches
+    let le_ip_key_index_model = IndexModel::builder().keys(doc! {"ip_key": 1}).build();
+    logents_coll
+        .create_index(le_ip_key_index_model, None)
+        .await?;
     // * second is on time alone; non-unique
-    let le_time_index_model = IndexModel::builder()
-        .keys(doc! {"time": 1})
-        .options(None)
-        .build();
-    logents_coll.create_index(le_time_index_model, None).await?;
+    // * it doubles as TTL index when retention.ttl_index is configured
+    let ttl_seconds = config.retention.ttl_seconds();
+    let index_names = logents_coll.list_index_names().await?;
+    if !index_names.contains(&"time_1".to_string()) {
+        let le_time_options = ttl_seconds.map(|secs| {
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(secs))
+                .build()
+        });
+        let le_time_index_model = IndexModel::builder()
+            .keys(doc! {"time": 1})
+            .options(le_time_options)
+            .build();
+        logents_coll.create_index(le_time_index_model, None).await?;
+    } else if let Some(secs) = ttl_seconds {
+        // * existing index: collMod adds or updates expiry in place
+        db.run_command(
+            doc! {
+                "collMod": "logentries",
+                "index": {"keyPattern": {"time": 1}, "expireAfterSeconds": secs as i64}
+            },
+            None,
+        )
+        .await?;
+    }
+    if fresh {
+        migrate::set_schema_version(&db, migrate::latest_version()).await?;
+    }
     Ok((db, host_data_coll, logents_coll))
 }
 
@@ -187,12 +273,44 @@ async fn ip_in_hdcoll(
     Ok(retval)
 }
 
-pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Result<()> {
+// * wait for the next item on a channel unless cancelled first
+async fn recv_unless_cancelled<T>(
+    rx: &mut mpsc::Receiver<T>,
+    cancel: &CancellationToken,
+) -> Option<T> {
+    tokio::select! {
+        item = rx.recv() => item,
+        _ = cancel.cancelled() => None,
+    }
+}
+
+pub async fn read(
+    daemon: &bool,
+    path: &PathBuf,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Counts> {
+    let started = Instant::now();
+    let span = info_span!("cycle", path = %path.display());
+    let result = read_cycle(daemon, path, config, cancel)
+        .instrument(span)
+        .await;
+    METRICS.cycle_finished(started, result.is_ok() && !cancel.is_cancelled());
+    result
+}
+
+async fn read_cycle(
+    daemon: &bool,
+    path: &PathBuf,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Counts> {
     /* Strategy: Parse loglines into LogEntries
     Do reverse dns lookup to generate RevLookupData, collect in map with ip as key
     Do geo lookup to generate Geodata, collect in map with ip as key
     Output to console
-    Eventually, send to mongo db
+    Send to mongo db in batches, recording a checkpoint after each batch
+    If cancelled, stop parsing and lookups, flush what is complete and record how far we got
      */
     let mut counts = Counts {
         n_inserted_les: 0,
@@ -202,16 +320,21 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
         n_unique_ips: 0,
     };
     // * setup database
-    let (_, host_data_coll, logents_coll) = setup_db(config).await?;
-
-    // * input stage
-    let file = read_lines(path);
-    let lines = match file {
-        Ok(file) => file,
+    let (db, host_data_coll, logents_coll) = setup_db(config).await?;
+
+    // * input stage; resume after the checkpoint left by the previous read
+    let inode =
+        checkpoint::inode(path).with_context(|| format!("Error reading log file {path:?}"))?;
+    let len = std::fs::metadata(path)?.len();
+    let saved = checkpoint::load(&db, path).await?;
+    let start = checkpoint::start_offset(saved.as_ref(), inode, len);
+    let lines = match read_lines(path, start) {
+        Ok(lines) => lines,
         Err(e) => bail!("Error reading log file: {e}"),
     };
     // * process each logline and collect parsed lines into Vec<LogEntry>
-    let logentries = make_logentries(lines);
+    let (logentries, parsed_to) = make_logentries(lines, start, &config.ua_filters, cancel);
+    let (logentries, offsets): (Vec<LogEntry>, Vec<u64>) = logentries.into_iter().unzip();
     counts.n_logents = logentries.len();
     // * end of input stage, resulting in raw logentries
 
@@ -219,10 +342,7 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
     let ip_set = logents_to_ips_set(&logentries);
     counts.n_unique_ips = ip_set.len();
 
-    let ip_arc_set: HashSet<Arc<String>> = ip_set
-        .into_iter()
-        .map(|ip| Arc::<String>::new(ip))
-        .collect();
+    let ip_arc_set: HashSet<Arc<String>> = ip_set.into_iter().map(Arc::<String>::new).collect();
 
     let mut ips_join_set: JoinSet<(Arc<String>, bool)> = JoinSet::new();
     for arcip in ip_arc_set {
@@ -230,11 +350,15 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
         let hdc = host_data_coll.clone();
         ips_join_set.spawn(async move { ip_in_hdcoll(ip, hdc).await.unwrap() });
     }
+    // * ips with hostdata, either already in db or looked up in this cycle
+    let mut known_ips: HashSet<String> = HashSet::new();
     let mut ips_rdns_data_needed = Vec::new();
     let mut ips_geodata_needed = Vec::new();
     while let Some(res) = ips_join_set.join_next().await {
         let (ip, is_in) = res?;
-        if !is_in {
+        if is_in {
+            known_ips.insert(ip.to_string());
+        } else if !cancel.is_cancelled() {
             ips_rdns_data_needed.push(ip.clone());
             ips_geodata_needed.push(ip.clone());
         }
@@ -243,6 +367,7 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
     // * --------------
 
     counts.n_new_ips = ips_rdns_data_needed.len();
+    METRICS.new_ips.inc_by(counts.n_new_ips as u64);
     let (pb_rdns, pb_geo) = progress_bar_setup(counts.n_unique_ips as u64);
 
     let mut ips_to_rdns_map: HashMap<String, RevLookupData> = HashMap::new();
@@ -271,13 +396,15 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
 
     //  only les associated with freshly looked up ips will be output here. Is that what is wanted?
     let mut ips_to_geodata_map: HashMap<String, geo::Geodata> = HashMap::new();
-    while let Some(geo_lookup_data) = rx_geo.recv().await {
+    while let Some(geo_lookup_data) = recv_unless_cancelled(&mut rx_geo, cancel).await {
         pb_geo.inc(1);
+        // * a long cycle that keeps making progress is alive
+        systemd::notify_watchdog();
         let ip = geo_lookup_data.ip.clone();
         ips_to_geodata_map.insert(ip.to_string(), geo_lookup_data);
     }
 
-    while let Some(rev_lookup_data) = rx_rdns.recv().await {
+    while let Some(rev_lookup_data) = recv_unless_cancelled(&mut rx_rdns, cancel).await {
         let ip = rev_lookup_data.ip_addr.clone();
         pb_rdns.inc(1);
         ips_to_rdns_map.insert(ip, rev_lookup_data);
@@ -291,6 +418,10 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
         println!("\nOutput");
     }
     for (ip, geodata) in ips_to_geodata_map {
+        // * when cancelled, only ips with both lookups complete get hostdata
+        let Some(rdns) = ips_to_rdns_map.get(&ip) else {
+            continue;
+        };
         if !daemon {
             println!(
                 "{}: {}",
@@ -299,9 +430,9 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
             );
             print!("{geodata}");
         }
-        let rdns = ips_to_rdns_map.get(&ip).unwrap();
         let hostdata = HostData {
             ip: ip.to_string(),
+            ip_key: cidr::ip_key(&ip),
             geodata,
             ptr_records: rdns.ptr_records.clone(),
         };
@@ -326,25 +457,61 @@ pub async fn read(daemon: &bool, path: &PathBuf, config: &Config) -> anyhow::Res
     if docs.len() > 0 {
         host_data_coll.insert_many(docs, None).await?;
     }
-    //  insertion of logentries is done synchronously with this logic. May want to change??
-    for le in logentries {
-        let result = logents_coll.insert_one(le, None).await;
-        match result {
-            Ok(_) => counts.n_inserted_les += 1,
-            Err(_) => counts.n_skipped_les += 1,
-        }
+    known_ips.extend(ip_to_hostdata_map.into_keys());
+
+    // * entries are written in file order up to the first one whose ip still lacks hostdata,
+    // * which only happens when cancelled; the checkpoint then stops before that entry
+    const INSERT_BATCH_SIZE: usize = 500;
+    let n_ready = logentries
+        .iter()
+        .take_while(|le| known_ips.contains(&le.ip))
+        .count();
+    for (batch, batch_offsets) in logentries[..n_ready]
+        .chunks(INSERT_BATCH_SIZE)
+        .zip(offsets[..n_ready].chunks(INSERT_BATCH_SIZE))
+    {
+        let skipped = transfer::insert_batch(&logents_coll, batch).await?;
+        let (n_inserted, n_skipped) = (batch.len() - skipped.len(), skipped.len());
+        counts.n_inserted_les += n_inserted;
+        counts.n_skipped_les += n_skipped;
+        METRICS.entries_inserted.inc_by(n_inserted as u64);
+        METRICS.entries_duplicate.inc_by(n_skipped as u64);
+        // * only entries new to the db are added to rollups
+        let inserted: Vec<LogEntry> = batch
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !skipped.contains(i))
+            .map(|(_, le)| le.clone())
+            .collect();
+        rollup::add_to_rollups(&db, &inserted).await?;
+        let batch_end = *batch_offsets.last().expect("chunks are never empty");
+        checkpoint::save(&db, path, inode, batch_end).await?;
+        systemd::notify_watchdog();
+    }
+    if n_ready == logentries.len() {
+        checkpoint::save(&db, path, inode, parsed_to).await?;
     }
 
     // * Display counts
-    let datetime = chrono::Utc::now();
-    println!("{datetime}: Read result: {:?}", counts);
+    info!(
+        n_logents = counts.n_logents,
+        n_unique_ips = counts.n_unique_ips,
+        n_new_ips = counts.n_new_ips,
+        n_inserted_les = counts.n_inserted_les,
+        n_skipped_les = counts.n_skipped_les,
+        "Read result"
+    );
     // * end of output stuff
 
+    if cancel.is_cancelled() {
+        // * abandon lookups still in flight; their results are no longer received
+        join_set.shutdown().await;
+    }
     while let Some(res) = join_set.join_next().await {
         res.expect("all async chans should finish");
     }
 
-    Ok(())
+    Ok(counts)
 }
 
 // given an ip, lookup hostdata
@@ -356,147 +523,298 @@ pub async fn get_hostdata(ip: &str, hd_coll: &HostDataColl) -> anyhow::Result<Ho
     }
 }
 
+// * how much search shows about each host's entries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostDetail {
+    Entries,
+    // * counts, first and last seen, status codes, top paths and user agents
+    Summary,
+    // * --no-logs
+    Nothing,
+}
+
+// * what search shows for each host
+#[derive(Debug, Clone, Copy)]
+struct HostOutput {
+    detail: HostDetail,
+    max_entries: Option<usize>,
+    // * keep hosts in the order found rather than sorting by ip
+    keep_order: bool,
+}
+
+// * entries for ip in time order, at most max_entries of them
+async fn find_entries(
+    current_logentries_coll: &Collection<LogEntry>,
+    ip: &str,
+    max_entries: Option<usize>,
+) -> anyhow::Result<mongodb::Cursor<LogEntry>> {
+    let options = FindOptions::builder()
+        .sort(doc! {"time": 1})
+        .limit(max_entries.map(|n| n as i64))
+        .build();
+    Ok(current_logentries_coll
+        .find(doc! {"ip": ip}, options)
+        .await?)
+}
+
 // output a vector of ips
 async fn output_ips(
-    suppress_logentry_output: bool,
+    host_output: HostOutput,
     hostdata_coll: &Collection<HostData>,
     current_logentries_coll: &Collection<LogEntry>,
     ips: &mut [String],
 ) -> anyhow::Result<()> {
-    ips.sort();
+    if !host_output.keep_order {
+        ips.sort();
+    }
+    let mut summaries = match host_output.detail {
+        HostDetail::Summary => query::host_summaries(current_logentries_coll, ips).await?,
+        _ => HashMap::new(),
+    };
     for ip in ips.iter() {
         let hd = get_hostdata(ip, hostdata_coll).await?;
         println!("{}", hd);
-        if !suppress_logentry_output {
-            let filter = doc! {"ip": ip};
-            let mut curs = current_logentries_coll.find(filter, None).await?;
-
-            while let Some(le) = curs.next().await {
-                let lex = le?;
-                println!("{}", lex);
+        match host_output.detail {
+            HostDetail::Entries => {
+                let mut curs =
+                    find_entries(current_logentries_coll, ip, host_output.max_entries).await?;
+
+                while let Some(le) = curs.next().await {
+                    let lex = le?;
+                    println!("{}", lex);
+                }
+            }
+            HostDetail::Summary => {
+                if let Some(summary) = summaries.remove(ip) {
+                    println!("{summary}");
+                }
             }
+            HostDetail::Nothing => (),
         }
     }
     Ok(())
 }
 
-async fn output_all_current_ips(
-    suppress_logentry_output: bool,
+// * entries for ip in time order, for machine readable output
+async fn entry_records(
+    current_logentries_coll: &Collection<LogEntry>,
+    ip: &str,
+    max_entries: Option<usize>,
+) -> anyhow::Result<Vec<EntryRecord>> {
+    let mut curs = find_entries(current_logentries_coll, ip, max_entries).await?;
+    let mut entries = vec![];
+    while let Some(le) = curs.next().await {
+        entries.push(EntryRecord::from(&le?));
+    }
+    Ok(entries)
+}
+
+// * print matched ips under a heading per distinct label, in the order given
+async fn output_grouped_ips(
+    host_output: HostOutput,
     hostdata_coll: &Collection<HostData>,
-    logents_coll: &Collection<LogEntry>,
     current_logentries_coll: &Collection<LogEntry>,
-    date_range: &DateRange,
+    heading: &str,
+    matched: Vec<(String, String)>,
 ) -> anyhow::Result<()> {
-    let mut ips_in_daterange = query::find_ips_in_daterange(logents_coll, date_range).await?;
-    output_ips(
-        suppress_logentry_output,
-        hostdata_coll,
-        current_logentries_coll,
-        &mut ips_in_daterange,
-    )
-    .await?;
+    let mut groups: Vec<(String, Vec<String>)> = vec![];
+    for (label, ip) in matched {
+        match groups.last_mut() {
+            Some((last, ips)) if *last == label => ips.push(ip),
+            _ => groups.push((label, vec![ip])),
+        }
+    }
+    for (label, mut ips) in groups {
+        println!(
+            "{}: {}\n----------",
+            style(heading).red(),
+            style(label).yellow()
+        );
+        output_ips(
+            host_output,
+            hostdata_coll,
+            current_logentries_coll,
+            &mut ips,
+        )
+        .await?;
+    }
     Ok(())
 }
 
+// * how table output of search is grouped, in place of grouping by country or org
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grouping {
+    // * by network, with separate IPv4 and IPv6 prefix lengths
+    Prefix(cidr::PrefixLens),
+    // * by registrable domain (eTLD+1) of the ip's hostname
+    Domain,
+}
+
+// * registrable domain of the first ptr record that has one
+fn ptr_domain(ptr_records: &[String]) -> String {
+    ptr_records
+        .iter()
+        .find_map(|name| lkup::registrable_domain(name))
+        .unwrap_or_else(|| "(no hostname)".to_string())
+}
+
+impl Grouping {
+    fn heading(&self) -> &'static str {
+        match self {
+            Grouping::Prefix(_) => "Network",
+            Grouping::Domain => "Domain",
+        }
+    }
+
+    // * matched hosts labelled with their group and ordered by it; within a group hosts keep
+    // * the order found
+    fn label(&self, matched: Vec<query::MatchedIp>) -> Vec<(String, query::MatchedIp)> {
+        match self {
+            Grouping::Prefix(lens) => {
+                // * ordered by network address; ips that do not parse are grouped under themselves
+                let mut networks: Vec<(Option<ipnet::IpNet>, query::MatchedIp)> = matched
+                    .into_iter()
+                    .map(|m| (m.ip.parse().ok().map(|ip| lens.network(ip)), m))
+                    .collect();
+                networks.sort_by_key(|(net, _)| *net);
+                networks
+                    .into_iter()
+                    .map(|(net, m)| (net.map_or_else(|| m.ip.clone(), |net| net.to_string()), m))
+                    .collect()
+            }
+            Grouping::Domain => {
+                let mut labelled: Vec<(String, query::MatchedIp)> = matched
+                    .into_iter()
+                    .map(|m| (ptr_domain(m.ptr_records.as_deref().unwrap_or_default()), m))
+                    .collect();
+                labelled.sort_by(|a, b| a.0.cmp(&b.0));
+                labelled
+            }
+        }
+    }
+}
+
 pub async fn search(
-    nologs: &Option<bool>,
-    start: &str,
-    end: &str,
-    ip: &Option<String>,
-    country: &Option<Vec<String>>,
-    org: &Option<String>,
+    detail: HostDetail,
+    range: &timespec::RangeArgs,
+    filters: &query::SearchFilters,
+    page: &query::PageArgs,
+    grouping: Option<Grouping>,
+    format: OutputFormat,
     config: &Config,
 ) -> anyhow::Result<()> {
-    let suppress_logentry_output: bool = match *nologs {
-        Some(true) => true,
-        Some(false) => false,
-        None => false,
+    let host_output = HostOutput {
+        detail,
+        max_entries: page.max_entries,
+        keep_order: page.is_ordered(),
     };
 
-    let date_range = query::time_str_to_daterange(start, end)?;
+    let date_range = range.date_range()?;
     let (loglook_db, hostdata_coll, logents_coll) = setup_db(config).await?;
     // * set up the temporary collection of logentries using the current daterange, name = "current_logentries"
-    query::make_current_le_coll(&date_range, &logents_coll).await?;
+    query::make_current_le_coll(&date_range, &filters.entries, &logents_coll).await?;
     let current_logentries_coll: mongodb::Collection<LogEntry> =
         loglook_db.collection("current_logentries");
-    if country.is_some() || ip.is_some() || org.is_some() {
-        match (ip, country, org) {
-            (None, Some(country), None) => {
-                // * get vec of all CountryWithIps in current daterange, then pass only those
-                //  * corresponding to an accepted country (as specified on command line)
-                // * or output
-                let country_with_ips_vec =
-                    query::get_current_ips_by_country(&current_logentries_coll).await?;
-                for mut country_with_ips in country_with_ips_vec {
-                    // * The country cli arg yields a vector of countries to accept
-                    // * If no countries are specified after --country flag, all are accepted
-                    let current_country = &country_with_ips.country;
-                    let accepted_countries = country;
-                    if accepted_countries.contains(current_country) || accepted_countries.is_empty()
-                    {
-                        println!(
-                            "{}: {}\n----------",
-                            style("Country").red(),
-                            style(country_with_ips.country).yellow()
-                        );
-                        output_ips(
-                            suppress_logentry_output,
-                            &hostdata_coll,
-                            &current_logentries_coll,
-                            &mut country_with_ips.ips,
-                        )
-                        .await?;
-                    };
-                }
-            }
-            // * search for ip with regex
-            (Some(ip), None, None) => {
-                let mut ips = query::find_ips_matching_regex(&current_logentries_coll, ip).await?;
-                output_ips(
-                    suppress_logentry_output,
-                    &hostdata_coll,
-                    &current_logentries_coll,
-                    &mut ips,
-                )
-                .await?;
+    // * all filters are applied together in one aggregation
+    let query::MatchedPage {
+        matched,
+        next_page_token,
+    } = query::find_matching_ips(&current_logentries_coll, filters, page).await?;
+    if format != OutputFormat::Table {
+        let mut summaries = match detail {
+            HostDetail::Summary => {
+                let ips: Vec<String> = matched.iter().map(|m| m.ip.clone()).collect();
+                query::host_summaries(&current_logentries_coll, &ips).await?
             }
-            // * search for org with regex
-            (None, None, Some(org)) => {
-                let orgs_with_ips = query::get_current_ips_by_org(&current_logentries_coll).await?;
-                let re = Regex::new(org)?;
-                for org_with_ips in orgs_with_ips {
-                    if re.is_match(&org_with_ips.org) {
-                        println!(
-                            "{}: {}\n----------",
-                            style("Organization").red(),
-                            style(&org_with_ips.org).yellow()
-                        );
-                        let mut ips = org_with_ips.ips;
-                        output_ips(
-                            suppress_logentry_output,
-                            &hostdata_coll,
-                            &current_logentries_coll,
-                            &mut ips,
-                        )
-                        .await?;
-                    }
-                }
-            }
-            _ => (),
+            _ => HashMap::new(),
+        };
+        let labelled: Vec<(Option<String>, query::MatchedIp)> = match grouping {
+            Some(grouping) => grouping
+                .label(matched)
+                .into_iter()
+                .map(|(label, m)| (Some(label), m))
+                .collect(),
+            None => matched.into_iter().map(|m| (None, m)).collect(),
         };
+        let mut hosts = vec![];
+        for (group, m) in &labelled {
+            let hostdata = hostdata_coll.find_one(doc! {"ip": &m.ip}, None).await?;
+            let entries = match detail {
+                HostDetail::Entries => {
+                    Some(entry_records(&current_logentries_coll, &m.ip, page.max_entries).await?)
+                }
+                _ => None,
+            };
+            let mut host = HostRecord::new(&m.ip, hostdata.as_ref(), entries).with_traffic(m);
+            host.group = group.clone();
+            host.summary = summaries.remove(&m.ip);
+            hosts.push(host);
+        }
+        output::write_hosts(
+            &mut std::io::stdout().lock(),
+            format,
+            &hosts,
+            detail == HostDetail::Entries,
+        )?;
+        // * stdout holds only the records, so the token goes to stderr
+        if let Some(token) = next_page_token {
+            eprintln!("Next page: --page-token {token}");
+        }
+        return Ok(());
+    }
+    // * grouped as asked; otherwise a country or org search is grouped under the matching countries or orgs
+    // * groups are ordered by label; within a group hosts keep the order found
+    let (heading, labelled): (&str, Vec<(String, String)>) = if let Some(grouping) = grouping {
+        let labelled = grouping
+            .label(matched)
+            .into_iter()
+            .map(|(label, m)| (label, m.ip))
+            .collect();
+        (grouping.heading(), labelled)
+    } else if filters.country.is_some() {
+        let mut labelled: Vec<(String, String)> = matched
+            .into_iter()
+            .map(|m| (m.country.unwrap_or_default(), m.ip))
+            .collect();
+        labelled.sort_by(|a, b| a.0.cmp(&b.0));
+        ("Country", labelled)
+    } else if filters.org.is_some() {
+        let mut labelled: Vec<(String, String)> = matched
+            .into_iter()
+            .map(|m| (m.org.unwrap_or_default(), m.ip))
+            .collect();
+        labelled.sort_by(|a, b| a.0.cmp(&b.0));
+        ("Organization", labelled)
     } else {
-        output_all_current_ips(
-            suppress_logentry_output,
+        let mut ips: Vec<String> = matched.into_iter().map(|m| m.ip).collect();
+        output_ips(
+            host_output,
             &hostdata_coll,
-            &logents_coll,
             &current_logentries_coll,
-            &date_range,
+            &mut ips,
         )
         .await?;
-    }
+        print_next_page(next_page_token);
+        return Ok(());
+    };
+    output_grouped_ips(
+        host_output,
+        &hostdata_coll,
+        &current_logentries_coll,
+        heading,
+        labelled,
+    )
+    .await?;
+    print_next_page(next_page_token);
     Ok(())
 }
 
+fn print_next_page(next_page_token: Option<String>) {
+    if let Some(token) = next_page_token {
+        println!("{}: --page-token {}", style("Next page").red(), token);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -511,34 +829,62 @@ mod tests {
 
     #[test]
     fn config_read_test() {
-        let config = read_config().unwrap();
+        let config = read_config(&ConfigSource::default()).unwrap();
         assert!(config.db_uri.contains("27017"));
         assert!(config.db_name.contains("loglook"));
     }
 
     #[test]
     fn test_search() {
-        let config = read_config().unwrap();
-        let nologs = None as Option<bool>;
-        let start = "2023-11-25T00:00:00Z";
-        let end = "2023-11-26T00:00:00Z";
-        let void_arg = None as Option<String>;
-        let void_vec = None as Option<Vec<String>>;
+        let config = read_config(&ConfigSource::default()).unwrap();
+        let range = timespec::RangeArgs {
+            start: Some("2023-11-25T00:00:00Z".to_string()),
+            end: Some("2023-11-26T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        let filters = query::SearchFilters::default();
         let res = aw!(search(
-            &nologs, start, end, &void_arg, &void_vec, &void_arg, &config
+            HostDetail::Entries,
+            &range,
+            &filters,
+            &query::PageArgs::default(),
+            None,
+            OutputFormat::Table,
+            &config
         ));
         tokio_test::assert_ok!(res);
     }
 
+    #[test]
+    fn test_read_lines_from_offset() {
+        let mut pbuf = std::env::temp_dir();
+        pbuf.push(format!("loglook-lines-{}.log", std::process::id()));
+        std::fs::write(&pbuf, "first\nsecond\npartial").unwrap();
+        let lines = read_lines(&pbuf, 0).unwrap();
+        // * the unterminated last line is left for the next read
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].text, "second");
+        assert_eq!(lines[1].end, 13);
+        let lines = read_lines(&pbuf, lines[0].end).unwrap();
+        std::fs::remove_file(&pbuf).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, "second");
+    }
+
     #[test]
     fn test_uptimer_filter() {
         let mut pbuf = PathBuf::new();
         pbuf.push("data/uptime-test.log");
-        let file = read_lines(&pbuf);
+        let file = read_lines(&pbuf, 0);
         let lines = file.unwrap();
         // * process each logline and collect parsed lines into Vec<LogEntry>
-        let logentries = make_logentries(lines);
-        for le in logentries {
+        let (logentries, _) = make_logentries(
+            lines,
+            0,
+            &config::default_ua_filters(),
+            &CancellationToken::new(),
+        );
+        for (le, _) in logentries {
             assert!(!le.ua.contains("uptimerobot"));
         }
     }
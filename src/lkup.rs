@@ -2,13 +2,17 @@ use console::style;
 use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use hickory_resolver::TokioAsyncResolver;
 
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
+use tracing::{debug, instrument};
+
+use crate::metrics::METRICS;
+
 #[derive(Debug)]
 pub struct RevLookupData {
     pub ip_addr: String,
@@ -32,14 +36,44 @@ impl fmt::Display for RevLookupData {
     }
 }
 
+// * registrable domain (eTLD+1) of a PTR name, e.g. "googleusercontent.com" for
+// * "12.34.56.78.bc.googleusercontent.com."; None for failed lookups like "timed out"
+// * only ICANN suffixes count, so private ones like compute-1.amazonaws.com do not split a provider
+pub fn registrable_domain(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let labels: Vec<&str> = name.split('.').collect();
+    if labels
+        .iter()
+        .any(|label| label.is_empty() || label.contains(char::is_whitespace))
+    {
+        return None;
+    }
+    // * an unlisted tld is a suffix of its own
+    let mut suffix_labels = 1;
+    for n in 2..=labels.len() {
+        let tail = labels[labels.len() - n..].join(".");
+        if let Some(suffix) = psl::suffix(tail.as_bytes()) {
+            if suffix.typ() == Some(psl::Type::Icann) && suffix.as_bytes().len() == tail.len() {
+                suffix_labels = n;
+            }
+        }
+    }
+    (labels.len() > suffix_labels).then(|| labels[labels.len() - suffix_labels - 1..].join("."))
+}
+
 // * Do reverse lookup on ip_str, send result out on channel tx
+#[instrument(skip(tx))]
 pub async fn lkup_hostnames(ip: &str, tx: mpsc::Sender<RevLookupData>) {
     const TIMEOUT_MS: u64 = 1000;
     let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
 
     let reverse_lookup = resolver.reverse_lookup(IpAddr::from_str(ip).unwrap());
     let timeout_duration = Duration::from_millis(TIMEOUT_MS);
+    let started = Instant::now();
     let lookup_result = timeout(timeout_duration, reverse_lookup).await;
+    METRICS
+        .rdns_latency
+        .observe(started.elapsed().as_secs_f64());
     let mut rev_lookup_data = RevLookupData::new(ip.to_string());
     match lookup_result {
         Ok(Ok(lookup_result)) => {
@@ -49,8 +83,51 @@ pub async fn lkup_hostnames(ip: &str, tx: mpsc::Sender<RevLookupData>) {
                 .map(|record| format!("{}", record))
                 .collect();
         }
-        Ok(Err(_)) => rev_lookup_data.ptr_records.push("unknown".to_string()), //no PTR records found,
-        Err(_) => rev_lookup_data.ptr_records.push("timed out".to_string()),   // lookup timed out
+        Ok(Err(_)) => {
+            //no PTR records found
+            METRICS.lookup_failed("rdns", "not_found");
+            debug!("no PTR records");
+            rev_lookup_data.ptr_records.push("unknown".to_string())
+        }
+        Err(_) => {
+            // lookup timed out
+            METRICS.lookup_failed("rdns", "timeout");
+            debug!("reverse lookup timed out");
+            rev_lookup_data.ptr_records.push("timed out".to_string())
+        }
     };
     tx.send(rev_lookup_data).await.expect("should just work");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrable_domains() {
+        assert_eq!(
+            registrable_domain("12.34.56.78.bc.googleusercontent.com.").as_deref(),
+            Some("googleusercontent.com")
+        );
+        assert_eq!(
+            registrable_domain("ec2-1-2-3-4.compute-1.amazonaws.com").as_deref(),
+            Some("amazonaws.com")
+        );
+        assert_eq!(
+            registrable_domain("host.Example.CO.UK.").as_deref(),
+            Some("example.co.uk")
+        );
+        // * multi-label public suffixes keep one label more
+        assert_eq!(
+            registrable_domain("a.b.shop.example.com.au.").as_deref(),
+            Some("example.com.au")
+        );
+        assert_eq!(
+            registrable_domain("mail.example.gov.uk").as_deref(),
+            Some("example.gov.uk")
+        );
+        // * a bare public suffix has no registrable domain
+        assert_eq!(registrable_domain("co.uk."), None);
+        assert_eq!(registrable_domain("timed out"), None);
+    }
+}
@@ -10,12 +10,17 @@ use std::fmt;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
     pub ip: String,
+    // * binary form of ip for range queries; absent in databases not yet migrated to v3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_key: Option<bson::Binary>,
     pub time: bson::DateTime,
     pub method: String,
     pub code: u32,
     pub nbytes: u32,
     pub referrer: String,
     pub ua: String,
+    // * left out by queries that do not need the raw line
+    #[serde(default)]
     pub line: String,
 }
 
@@ -33,6 +38,13 @@ impl fmt::Display for LogEntry {
     }
 }
 
+impl LogEntry {
+    // * request path, i.e. the middle part of "GET /path HTTP/1.1"; empty for malformed requests
+    pub fn path(&self) -> &str {
+        self.method.split_whitespace().nth(1).unwrap_or("")
+    }
+}
+
 fn get_re_match_part(caps: &Captures<'_>, part_name: &str) -> String {
     let part = caps.name(part_name).unwrap().as_str();
     String::from(part)
@@ -63,6 +75,7 @@ impl TryFrom<&String> for LogEntry {
                 .expect("should be valid time fmt");
         let ct_utc: chrono::DateTime<chrono::Utc> = ct_time_fixed.into();
         let le = LogEntry {
+            ip_key: crate::cidr::ip_key(&ip_str),
             ip: ip_str.to_string(),
             time: ct_utc.into(),
             method: get_re_match_part(&caps, "method"),
@@ -77,7 +90,6 @@ impl TryFrom<&String> for LogEntry {
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
 
@@ -87,6 +99,7 @@ mod tests {
         let le = LogEntry::try_from(&line).unwrap();
         assert_eq!(le.line, line);
         assert_eq!(le.code, 404);
+        assert_eq!(le.path(), "/stalker_portal/server/tools/auth_simple.php");
     }
 
     #[test]
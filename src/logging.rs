@@ -0,0 +1,72 @@
+// * diagnostics go through tracing to stderr; stdout is left for command output
+use std::io::IsTerminal;
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+    // * native journald fields, for running as a systemd service
+    Journald,
+}
+
+// * verbosity is the number of -v flags minus the number of -q flags; 0 means info
+pub fn level_for(verbosity: i8) -> LevelFilter {
+    match verbosity {
+        i8::MIN..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+// * RUST_LOG, if set, overrides the level from -v/-q
+fn filter(verbosity: i8) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(level_for(verbosity).into())
+        .from_env_lossy()
+}
+
+pub fn init(verbosity: i8, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(verbosity))
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.with_ansi(std::io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+        // * fall back to text on stderr when there is no journal to talk to
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(layer) => tracing_subscriber::registry()
+                .with(filter(verbosity))
+                .with(layer)
+                .init(),
+            Err(e) => {
+                builder.with_ansi(std::io::stderr().is_terminal()).init();
+                warn!("journald unavailable, logging to stderr: {e}");
+            }
+        },
+    }
+}
+
+// * progress bars only make sense when someone is watching
+pub fn is_interactive() -> bool {
+    std::io::stdout().is_terminal() && std::io::stderr().is_terminal()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_levels() {
+        assert_eq!(level_for(0), LevelFilter::INFO);
+        assert_eq!(level_for(2), LevelFilter::TRACE);
+        assert_eq!(level_for(-1), LevelFilter::WARN);
+        assert_eq!(level_for(-5), LevelFilter::ERROR);
+    }
+}
@@ -1,39 +1,213 @@
-use clap::{ArgAction, Parser, Subcommand};
+use chrono_tz::Tz;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use loglook::cidr::PrefixLens;
+use loglook::lease::Lease;
+use loglook::logging::LogFormat;
+use loglook::output::OutputFormat;
+use loglook::query::{EntryFilters, PageArgs, SearchFilters};
+use loglook::systemd;
+use loglook::timeline::{BucketSize, Chart, Series, Split};
+use loglook::timespec::RangeArgs;
+use loglook::transfer::FileFormat;
+use loglook::{ConfigSource, Grouping, HostDetail};
 use std::path::PathBuf;
 use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::time::sleep;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+// * time allowed after a shutdown signal for the current cycle to flush and checkpoint
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
 
 // * https://rust-cli-recommendations.sunshowers.io/handling-arguments.html
 #[derive(Debug, Parser)]
 #[clap(name = "loglook", version = "0.3", about = "Log Reader")]
 pub struct App {
-    // #[clap(flatten)]
-    // global_opts: GlobalOpts,
+    #[clap(flatten)]
+    global_opts: GlobalOpts,
     #[clap(subcommand)]
     command: Command,
 }
 
+#[derive(Debug, Args)]
+struct GlobalOpts {
+    /// more diagnostics; repeat for more
+    #[clap(long, short, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// fewer diagnostics; repeat for fewer
+    #[clap(long, short, global = true, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// format of diagnostics written to stderr
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// config file; default ~/.loglook/config.toml. LOGLOOK_<KEY> overrides a setting, e.g.
+    /// LOGLOOK_DAEMON__INTERVAL=30m; lists are comma separated, e.g. LOGLOOK_UA_FILTERS=a,b
+    #[clap(long, global = true, env = "LOGLOOK_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// apply settings from the [profile.<name>] section of the config file
+    #[clap(long, global = true, env = "LOGLOOK_PROFILE")]
+    profile: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct ReadArgs {
+    /// Run as daemon
+    #[clap(long, short = 'd')]
+    daemon: bool,
+
+    /// time between reads in daemon mode, e.g. 30m; default from config, else 30m
+    #[clap(long, short = 'n')]
+    interval: Option<String>,
+
+    /// in daemon mode, also read soon after the logfile changes
+    #[clap(long, short = 'w')]
+    watch: bool,
+
+    /// with --watch, quiet time after a change before reading, e.g. 2s
+    #[clap(long)]
+    debounce: Option<String>,
+
+    /// in daemon mode, serve prometheus metrics at http://<addr>/metrics
+    #[clap(long)]
+    metrics_addr: Option<String>,
+
+    /// The path to read logfile from
+    path: std::path::PathBuf,
+}
+
+// * parsed once per run, so variant sizes don't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Read the specified logfile
-    Read {
-        /// Run as daemon
-        #[clap(long, short = 'd')]
-        daemon: bool,
-
-        /// The path to read logfile from
-        path: std::path::PathBuf,
-        // (can #[clap(flatten)] other argument structs here)
-    },
+    Read(ReadArgs),
     /// Find ips in date range
     Search {
         #[clap(long="no-logs", short, action=ArgAction::SetTrue)]
         /// no output of logentries
         nologs: Option<bool>,
 
+        /// per-host summary instead of logentries: counts, status codes, top paths and user agents
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "nologs")]
+        summary: bool,
+
+        #[clap(flatten)]
+        range: RangeArgs,
+
+        /// output format
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: OutputFormat,
+
+        /// group hosts by network, e.g. 24 or 24,48 for IPv4 and IPv6 prefix lengths; table
+        /// output gets a heading per group, other formats a group field per host
+        #[clap(long, value_name = "LENS")]
+        group_by_prefix: Option<PrefixLens>,
+
+        /// group hosts by registrable domain of the hostname, e.g. amazonaws.com; as with
+        /// --group-by-prefix
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "group_by_prefix")]
+        group_by_domain: bool,
+
+        #[clap(flatten)]
+        page: PageArgs,
+
+        /// filters may be combined; all must match
+        #[clap(flatten)]
+        filters: SearchFilters,
+    },
+    /// Report totals and top-N breakdowns of traffic in a time range
+    Stats {
+        #[clap(flatten)]
+        range: RangeArgs,
+
+        /// number of entries in each top list
+        #[clap(long, short, default_value = "10")]
+        top: usize,
+
+        /// output format
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: OutputFormat,
+
+        #[clap(flatten)]
+        filters: EntryFilters,
+    },
+    /// Show requests per minute, hour or day as a chart or table
+    Timeline {
+        #[clap(flatten)]
+        range: RangeArgs,
+
+        /// bucket size; hours and days start in --tz
+        #[clap(long, short, value_enum, default_value = "hour")]
+        bucket: BucketSize,
+
+        /// split counts into a series per status class or country
+        #[clap(long, value_enum, conflicts_with = "ip")]
+        split: Option<Split>,
+
+        /// split counts into this ip and all others
+        #[clap(long)]
+        ip: Option<String>,
+
+        /// chart drawn for table output
+        #[clap(long, value_enum, default_value = "bars")]
+        chart: Chart,
+
+        /// output format
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: OutputFormat,
+
+        #[clap(flatten)]
+        filters: EntryFilters,
+    },
+    /// Compare two time ranges: what appeared, disappeared or changed from A to B, by ip,
+    /// country, organization and path (ASNs are not stored, so they are not compared)
+    Diff {
+        /// earlier range: today, a day like 2024-12-09, a span like 7d, or START..END
+        /// where either side is a time or a span back from now, e.g. 14d..7d
+        #[clap(long = "a", value_name = "RANGE")]
+        a: String,
+
+        /// later range, same forms as --a
+        #[clap(long = "b", value_name = "RANGE")]
+        b: String,
+
+        /// timezone for days and times without an offset; default local time
+        #[clap(long)]
+        tz: Option<Tz>,
+
+        /// number of keys in each list
+        #[clap(long, short, default_value = "20")]
+        top: usize,
+
+        /// output format
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Show everything known about one ip, with other known ips in its /24 and in its
+    /// organization; ASNs are not stored, so organization neighbours stand in for ASN ones
+    Host {
+        /// the ip address
+        ip: String,
+
+        /// number of entries in each top list
+        #[clap(long, short, default_value = "10")]
+        top: usize,
+
+        /// output format
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Summarize traffic in date range from rollups
+    Summary {
         /// start time, e.g. ISO: 2023-12-29T00:00:00Z
         #[clap(long, short)]
         start: String,
@@ -42,79 +216,480 @@ enum Command {
         #[clap(long, short)]
         end: String,
 
-        /// regex search by IP address
-        #[clap(long, short, group = "select")]
-        ip: Option<String>,
+        /// use hourly instead of daily rollups
+        #[clap(long)]
+        hourly: bool,
+    },
+    /// Rebuild hourly and daily rollups for date range
+    Rollup {
+        /// start time, e.g. ISO: 2023-12-29T00:00:00Z
+        #[clap(long, short)]
+        start: String,
 
-        /// regex search by country
-        #[clap(long, short, group = "select")]
-        #[arg(num_args(0..))]
-        country: Option<Vec<String>>,
+        /// end time e.g. ISO: 2023-12-29T00:00:00Z
+        #[clap(long, short)]
+        end: String,
+    },
+    /// Manage the database schema
+    Db {
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// Export log entries and host data in date range to files
+    Export {
+        /// start time, e.g. ISO: 2023-12-29T00:00:00Z
+        #[clap(long, short)]
+        start: String,
 
-        /// regex search by organization
-        #[clap(long, short, group = "select")]
-        org: Option<String>,
+        /// end time e.g. ISO: 2023-12-29T00:00:00Z
+        #[clap(long, short)]
+        end: String,
+
+        /// file format
+        #[clap(long, short, value_enum, default_value = "ndjson")]
+        format: FileFormat,
+
+        /// directory to write logentries and hostdata files to
+        dir: PathBuf,
+    },
+    /// Import log entries and host data written by export
+    Import {
+        /// file format
+        #[clap(long, short, value_enum, default_value = "ndjson")]
+        format: FileFormat,
+
+        /// directory to read logentries and hostdata files from
+        dir: PathBuf,
+    },
+    /// Remove log entries older than the retention period
+    Prune {
+        /// age cutoff, e.g. 90d; defaults to retention.logentry_days in config
+        #[clap(long)]
+        older_than: Option<String>,
+
+        /// only count what would be removed
+        #[clap(long)]
+        dry_run: bool,
+
+        /// also remove hostdata for ips with no remaining log entries
+        #[clap(long)]
+        orphans: bool,
+    },
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a systemd unit for running the read daemon
+    Unit {
+        /// user to run the daemon as
+        #[clap(long)]
+        user: String,
+
+        /// logfile for the daemon to read
+        #[clap(default_value = "/var/log/nginx/access.log")]
+        logfile: PathBuf,
     },
 }
 
-async fn read(daemon: &bool, path: &PathBuf, config: &loglook::Config) -> anyhow::Result<()> {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Check config, database, geo lookup and reverse dns, exiting non-zero on failure
+    Check,
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommand {
+    /// Apply pending schema migrations
+    Migrate,
+    /// Report schema version and pending migrations
+    Status,
+}
+
+// * cli value wins over config value, which wins over default
+fn seconds_from(cli: &Option<String>, conf: &Option<String>, default: &str) -> anyhow::Result<u64> {
+    let spec = cli.as_deref().or(conf.as_deref()).unwrap_or(default);
+    let seconds = loglook::timespec::parse_duration(spec)?.num_seconds();
+    if seconds < 1 {
+        anyhow::bail!("Duration {spec:?} must be at least 1s");
+    }
+    Ok(seconds as u64)
+}
+
+// * run one read cycle; once cancelled, give it SHUTDOWN_GRACE to finish before abandoning it
+async fn read_cycle(
+    daemon: &bool,
+    path: &PathBuf,
+    config: &loglook::Config,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Option<loglook::Counts>> {
+    let cycle = loglook::read(daemon, path, config, cancel);
+    tokio::pin!(cycle);
+    tokio::select! {
+        res = &mut cycle => res.map(Some),
+        _ = cancel.cancelled() => match timeout(SHUTDOWN_GRACE, &mut cycle).await {
+            Ok(res) => res.map(Some),
+            Err(_) => {
+                warn!("Read cycle still running {SHUTDOWN_GRACE:?} after shutdown; abandoning it");
+                Ok(None)
+            }
+        },
+    }
+}
+
+// * the metrics listener; a reload that changes its address replaces it
+struct MetricsServer {
+    stop: CancellationToken,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MetricsServer {
+    async fn start(addr: &str, cancel: &CancellationToken) -> anyhow::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Serving metrics on http://{addr}/metrics");
+        let stop = cancel.child_token();
+        let task = tokio::spawn(loglook::metrics::serve(listener, stop.clone()));
+        Ok(MetricsServer { stop, task })
+    }
+
+    // * waits for the listener to close, so its address can be bound again
+    async fn stop(self) {
+        self.stop.cancel();
+        let _ = self.task.await;
+    }
+}
+
+// * a lease left behind expires on its own, so failing to release is not fatal
+async fn release(lease: &Lease) {
+    if let Err(e) = lease.release().await {
+        warn!("Failed to release lease: {e:#}");
+    }
+}
+
+async fn read(
+    args: &ReadArgs,
+    config: &loglook::Config,
+    source: &ConfigSource,
+) -> anyhow::Result<()> {
+    let ReadArgs {
+        daemon,
+        interval,
+        watch,
+        debounce,
+        metrics_addr,
+        path,
+    } = args;
+    config.validate()?;
+    let mut config = config.clone();
+    let mut interval_secs = seconds_from(interval, &config.daemon.interval, "30m")?;
+    let mut debounce_time =
+        Duration::from_secs(seconds_from(debounce, &config.daemon.debounce, "2s")?);
+    let mut watching = *daemon && (*watch || config.daemon.watch);
+    let mut watcher = if watching {
+        Some(loglook::watch::FileWatcher::new(path)?)
+    } else {
+        None
+    };
+    // * ctrl-c and SIGTERM cancel the token, which also interrupts a cycle in progress
+    let cancel = CancellationToken::new();
+    let c = cancel.clone();
     ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
+        c.cancel();
     })?;
-    // * read every 30 minutes
-    // TODO add variable duration???
-    // * wake up once a second to check for ctrl-c; rerun main fn when wait reduced to 0
+    // * the cli address wins over the config one
+    let mut serving_addr = match daemon {
+        true => metrics_addr.clone().or(config.daemon.metrics_addr.clone()),
+        false => None,
+    };
+    let mut metrics_server = match &serving_addr {
+        Some(addr) => Some(MetricsServer::start(addr, &cancel).await?),
+        None => None,
+    };
+    // * SIGHUP asks for the config file to be re-read between cycles; the signal is only noted
+    // * here, so that a debounce in progress is not cut short
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let reload_requested = reload_requested.clone();
+        async move {
+            while hangup.recv().await.is_some() {
+                reload_requested.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    // * refuse to start if another instance is reading this file into this database
+    // * acquired last, so every later exit path releases it
+    let lease = Arc::new(Lease::acquire(&config, path).await?);
+    let holding = tokio::spawn({
+        let lease = lease.clone();
+        let cancel = cancel.clone();
+        async move { lease.hold(cancel).await }
+    });
+    // * watchdog pings come from the idle loop and from a cycle as it makes progress, so only
+    // * a stalled cycle trips the watchdog
+    let watchdog_every = systemd::watchdog_timeout().map(|t| t / 2);
+    let mut last_ping = Instant::now();
+    systemd::notify_ready();
+    // * wake up once a second; rerun main fn when wait reduced to 0
+    // * in watch mode a change to the logfile also reruns main fn
     let mut seconds_till_run = 0;
-    while running.load(Ordering::SeqCst) {
+    while !cancel.is_cancelled() {
         if seconds_till_run == 0 {
-            seconds_till_run = 1800; // reset to 30 minutes
-            loglook::read(daemon, path, config).await?;
+            seconds_till_run = interval_secs;
+            let counts = read_cycle(daemon, path, &config, &cancel).await;
+            if let Ok(Some(counts)) = &counts {
+                systemd::notify_status(&systemd::status_line(counts));
+            }
+            if let Err(e) = counts {
+                systemd::notify_stopping();
+                release(&lease).await;
+                return Err(e);
+            }
         }
 
-        if *daemon {
-            sleep(Duration::from_secs(1)).await;
-            seconds_till_run -= 1;
-        } else {
+        if !*daemon {
             break;
         }
+        if let Some(every) = watchdog_every {
+            if last_ping.elapsed() >= every {
+                systemd::notify_watchdog();
+                last_ping = Instant::now();
+            }
+        }
+        let changed = tokio::select! {
+            changed = async {
+                match watcher.as_mut() {
+                    Some(watcher) => watcher.changed(Duration::from_secs(1), debounce_time).await,
+                    None => {
+                        sleep(Duration::from_secs(1)).await;
+                        false
+                    }
+                }
+            } => changed,
+            _ = cancel.cancelled() => false,
+        };
+        if reload_requested.swap(false, Ordering::Relaxed) {
+            // * swap in the new config only when all of it is valid
+            let reloaded =
+                loglook::reload_config(&config, source).and_then(|new_config| match new_config {
+                    Some(new_config) => {
+                        // * the lease is held on the current database only
+                        if new_config.db_uri != config.db_uri
+                            || new_config.db_name != config.db_name
+                        {
+                            anyhow::bail!(
+                                "db_uri and db_name cannot change on reload; restart instead"
+                            );
+                        }
+                        let new_interval =
+                            seconds_from(interval, &new_config.daemon.interval, "30m")?;
+                        let new_debounce =
+                            seconds_from(debounce, &new_config.daemon.debounce, "2s")?;
+                        Ok(Some((new_config, new_interval, new_debounce)))
+                    }
+                    None => Ok(None),
+                });
+            match reloaded {
+                Ok(Some((new_config, new_interval, new_debounce))) => {
+                    let new_watching = *daemon && (*watch || new_config.daemon.watch);
+                    if new_watching != watching {
+                        watching = new_watching;
+                        watcher = if watching {
+                            match loglook::watch::FileWatcher::new(path) {
+                                Ok(new_watcher) => {
+                                    info!("Config reload: watching {}", path.display());
+                                    Some(new_watcher)
+                                }
+                                Err(e) => {
+                                    error!("Config reload: cannot watch logfile: {e:#}");
+                                    None
+                                }
+                            }
+                        } else {
+                            info!("Config reload: no longer watching {}", path.display());
+                            None
+                        };
+                    }
+                    let new_addr = match daemon {
+                        true => metrics_addr
+                            .clone()
+                            .or(new_config.daemon.metrics_addr.clone()),
+                        false => None,
+                    };
+                    if new_addr != serving_addr {
+                        if let Some(server) = metrics_server.take() {
+                            server.stop().await;
+                        }
+                        serving_addr = new_addr;
+                        match &serving_addr {
+                            Some(addr) => match MetricsServer::start(addr, &cancel).await {
+                                Ok(server) => metrics_server = Some(server),
+                                Err(e) => {
+                                    error!("Config reload: cannot serve metrics on {addr}: {e:#}")
+                                }
+                            },
+                            None => info!("Config reload: metrics no longer served"),
+                        }
+                    }
+                    config = new_config;
+                    interval_secs = new_interval;
+                    debounce_time = Duration::from_secs(new_debounce);
+                    seconds_till_run = seconds_till_run.min(interval_secs);
+                }
+                Ok(None) => info!("Config reload: no changes"),
+                Err(e) => error!("Config reload failed, keeping current config: {e:#}"),
+            }
+        }
+        if changed {
+            seconds_till_run = 0;
+        } else {
+            seconds_till_run -= 1;
+        }
     }
 
-    println!("Exiting gracefully!");
+    systemd::notify_stopping();
+    // * a lost lease is a failure, so systemd restarts the daemon
+    if let Ok(Err(lost)) = holding.await {
+        return Err(lost);
+    }
+    release(&lease).await;
+    info!("Exiting gracefully!");
     Ok(())
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() {
     let cli = App::parse();
-    let config = loglook::read_config();
+    let verbosity =
+        (cli.global_opts.verbose.min(8) as i8).saturating_sub(cli.global_opts.quiet.min(8) as i8);
+    loglook::logging::init(verbosity, cli.global_opts.log_format);
+    loglook::output::init_colors();
+    // * printing a unit file needs no config or database
+    if let Command::Unit { user, logfile } = &cli.command {
+        let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("loglook"));
+        print!(
+            "{}",
+            systemd::unit_file(&exe.to_string_lossy(), user, &logfile.to_string_lossy())
+        );
+        process::exit(0);
+    }
+    let source = ConfigSource {
+        path: cli.global_opts.config.clone(),
+        profile: cli.global_opts.profile.clone(),
+    };
+    // * config check reports an unreadable config itself
+    if let Command::Config {
+        command: ConfigCommand::Check,
+    } = &cli.command
+    {
+        match loglook::check::check(&source).await {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                error!("{e:#}");
+                process::exit(1);
+            }
+        }
+    }
+    let config = loglook::read_config(&source);
     let conf = match config {
         Ok(config) => config,
         Err(e) => {
-            eprintln!("Application error: {}", e);
+            error!("Application error: {:#}", e);
             process::exit(1);
         }
     };
 
     // let args = cli.command
     let result = match &cli.command {
-        #[allow(unused_variables)]
-        Command::Read { daemon, path } => read(daemon, path, &conf).await,
+        Command::Read(args) => read(args, &conf, &source).await,
         Command::Search {
             nologs,
+            summary,
+            range,
+            format,
+            group_by_prefix,
+            group_by_domain,
+            page,
+            filters,
+        } => {
+            let grouping = match (group_by_prefix, group_by_domain) {
+                (Some(lens), _) => Some(Grouping::Prefix(*lens)),
+                (None, true) => Some(Grouping::Domain),
+                (None, false) => None,
+            };
+            let detail = match (nologs, summary) {
+                (Some(true), _) => HostDetail::Nothing,
+                (_, true) => HostDetail::Summary,
+                _ => HostDetail::Entries,
+            };
+            loglook::search(detail, range, filters, page, grouping, *format, &conf).await
+        }
+        Command::Stats {
+            range,
+            top,
+            format,
+            filters,
+        } => loglook::stats::stats(range, filters, *top, *format, &conf).await,
+        Command::Timeline {
+            range,
+            bucket,
+            split,
+            ip,
+            chart,
+            format,
+            filters,
+        } => {
+            let series = match (split, ip) {
+                (Some(split), _) => Series::By(*split),
+                (None, Some(ip)) => Series::Ip(ip.clone()),
+                (None, None) => Series::All,
+            };
+            loglook::timeline::timeline(range, filters, *bucket, &series, *chart, *format, &conf)
+                .await
+        }
+        Command::Diff {
+            a,
+            b,
+            tz,
+            top,
+            format,
+        } => {
+            let range = RangeArgs {
+                tz: *tz,
+                ..Default::default()
+            };
+            loglook::diff::diff(a, b, &range, *top, *format, &conf).await
+        }
+        Command::Host { ip, top, format } => loglook::host::host(ip, *top, *format, &conf).await,
+        Command::Summary { start, end, hourly } => {
+            loglook::rollup::summary(start, end, hourly, &conf).await
+        }
+        Command::Rollup { start, end } => loglook::rollup::rebuild(start, end, &conf).await,
+        Command::Db { command } => match command {
+            DbCommand::Migrate => loglook::migrate::migrate(&conf).await,
+            DbCommand::Status => loglook::migrate::status(&conf).await,
+        },
+        Command::Export {
             start,
             end,
-            ip,
-            country,
-            org,
-        } => loglook::search(nologs, start, end, ip, country, org, &conf).await,
+            format,
+            dir,
+        } => loglook::transfer::export(start, end, format, dir, &conf).await,
+        Command::Import { format, dir } => loglook::transfer::import(format, dir, &conf).await,
+        Command::Prune {
+            older_than,
+            dry_run,
+            orphans,
+        } => loglook::prune::prune(older_than, dry_run, orphans, &conf).await,
+        Command::Config { .. } | Command::Unit { .. } => {
+            unreachable!("handled before reading config")
+        }
     };
 
     match result {
         Ok(()) => process::exit(0),
         Err(e) => {
-            eprintln!("Application error: {}", e);
+            error!("Application error: {:#}", e);
             process::exit(1);
         }
     }
@@ -0,0 +1,213 @@
+// * prometheus metrics for the ingest daemon, optionally served over http at /metrics
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+pub struct Metrics {
+    registry: Registry,
+    pub lines_parsed: IntCounter,
+    pub parse_errors: IntCounter,
+    pub entries_inserted: IntCounter,
+    pub entries_duplicate: IntCounter,
+    pub new_ips: IntCounter,
+    pub geo_latency: Histogram,
+    pub rdns_latency: Histogram,
+    // * labels: lookup (geo, rdns) and reason
+    pub lookup_failures: IntCounterVec,
+    pub cycle_duration: Histogram,
+    pub last_success: IntGauge,
+}
+
+fn counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("metric name should be valid");
+    registry
+        .register(Box::new(counter.clone()))
+        .expect("metric should register once");
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, buckets: Vec<f64>) -> Histogram {
+    let histogram = Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets))
+        .expect("metric name should be valid");
+    registry
+        .register(Box::new(histogram.clone()))
+        .expect("metric should register once");
+    histogram
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("loglook".to_string()), None)
+            .expect("prefix should be valid");
+        let lookup_buckets = vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+        let lookup_failures = IntCounterVec::new(
+            Opts::new("lookup_failures_total", "Failed lookups by kind and reason"),
+            &["lookup", "reason"],
+        )
+        .expect("metric name should be valid");
+        registry
+            .register(Box::new(lookup_failures.clone()))
+            .expect("metric should register once");
+        let last_success = IntGauge::new(
+            "last_success_timestamp_seconds",
+            "Unix time of the last read cycle that completed without error",
+        )
+        .expect("metric name should be valid");
+        registry
+            .register(Box::new(last_success.clone()))
+            .expect("metric should register once");
+        Metrics {
+            lines_parsed: counter(&registry, "lines_parsed_total", "Log lines examined"),
+            parse_errors: counter(
+                &registry,
+                "parse_errors_total",
+                "Log lines that failed to parse",
+            ),
+            entries_inserted: counter(&registry, "entries_inserted_total", "Log entries stored"),
+            entries_duplicate: counter(
+                &registry,
+                "entries_duplicate_total",
+                "Log entries skipped as already stored",
+            ),
+            new_ips: counter(
+                &registry,
+                "new_ips_total",
+                "IPs looked up for the first time",
+            ),
+            geo_latency: histogram(
+                &registry,
+                "geo_lookup_seconds",
+                "Geo lookup latency",
+                lookup_buckets.clone(),
+            ),
+            rdns_latency: histogram(
+                &registry,
+                "rdns_lookup_seconds",
+                "Reverse dns lookup latency",
+                lookup_buckets,
+            ),
+            lookup_failures,
+            cycle_duration: histogram(
+                &registry,
+                "cycle_seconds",
+                "Duration of a read cycle",
+                vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0],
+            ),
+            last_success,
+            registry,
+        }
+    }
+
+    pub fn lookup_failed(&self, lookup: &str, reason: &str) {
+        self.lookup_failures
+            .with_label_values(&[lookup, reason])
+            .inc();
+    }
+
+    // * record how long a cycle took and, if it succeeded, when
+    pub fn cycle_finished(&self, started: Instant, succeeded: bool) {
+        self.cycle_duration.observe(started.elapsed().as_secs_f64());
+        if succeeded {
+            self.last_success.set(chrono::Utc::now().timestamp());
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("text encoding should not fail");
+        String::from_utf8(buf).expect("text encoding should be utf8")
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+async fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+    // * only the request line matters; the rest of the request is ignored
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let response = if request.starts_with("GET /metrics ") {
+        let body = METRICS.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub async fn serve(listener: TcpListener, cancel: CancellationToken) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = cancel.cancelled() => return,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream).await {
+                        warn!("Metrics request error: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Metrics accept error: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    async fn get(path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve(listener, cancel.clone()));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        cancel.cancel();
+        server.await.unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics() {
+        METRICS.lines_parsed.inc();
+        METRICS.lookup_failed("geo", "timeout");
+        let response = aw!(get("/metrics"));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("loglook_lines_parsed_total"));
+        assert!(
+            response.contains(r#"loglook_lookup_failures_total{lookup="geo",reason="timeout"}"#)
+        );
+    }
+
+    #[test]
+    fn other_paths_not_found() {
+        let response = aw!(get("/"));
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
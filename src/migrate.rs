@@ -0,0 +1,231 @@
+// * schema versioning for the mongo collections
+// * version is kept in the meta collection; migrations run in order and must be idempotent
+use anyhow::bail;
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{FindOneOptions, UpdateOptions};
+use mongodb::{Collection, Database, IndexModel};
+use tracing::info;
+
+use crate::log_entries::LogEntry;
+use crate::query::DateRange;
+use crate::{cidr, rollup, Config};
+
+const SCHEMA_VERSION_ID: &str = "schema_version";
+
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "drop legacy ip+time unique index and rebuild logentries unique index",
+    },
+    Migration {
+        version: 2,
+        description: "backfill hourly and daily rollups from existing logentries",
+    },
+    Migration {
+        version: 3,
+        description: "add binary ip_key to logentries and hostdata for CIDR searches",
+    },
+];
+
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn pending(current: i32) -> Vec<&'static Migration> {
+    MIGRATIONS.iter().filter(|m| m.version > current).collect()
+}
+
+pub async fn get_schema_version(db: &Database) -> anyhow::Result<i32> {
+    let meta: Collection<Document> = db.collection("meta");
+    let maybe_doc = meta.find_one(doc! {"_id": SCHEMA_VERSION_ID}, None).await?;
+    // * databases created before versioning have no meta document
+    let version = match maybe_doc {
+        Some(doc) => doc.get_i32("version")?,
+        None => 0,
+    };
+    Ok(version)
+}
+
+pub(crate) async fn set_schema_version(db: &Database, version: i32) -> anyhow::Result<()> {
+    let meta: Collection<Document> = db.collection("meta");
+    let now: bson::DateTime = chrono::Utc::now().into();
+    let options = UpdateOptions::builder().upsert(true).build();
+    meta.update_one(
+        doc! {"_id": SCHEMA_VERSION_ID},
+        doc! {"$set": {"version": version, "updated": now}},
+        options,
+    )
+    .await?;
+    Ok(())
+}
+
+// * early databases had a unique index on ip+time only, which rejected distinct requests;
+// * it is found by its keys since its name depended on how it was created
+async fn rebuild_logentries_unique_index(db: &Database) -> anyhow::Result<()> {
+    let logents_coll: Collection<LogEntry> = db.collection("logentries");
+    let indexes: Vec<IndexModel> = logents_coll.list_indexes(None).await?.try_collect().await?;
+    for index in indexes {
+        let options = index.options.unwrap_or_default();
+        if is_legacy_unique_keys(&index.keys) && options.unique == Some(true) {
+            if let Some(name) = options.name {
+                logents_coll.drop_index(name, None).await?;
+            }
+        }
+    }
+    // * setup_db recreates the current unique index if it is missing
+    Ok(())
+}
+
+// * {ip: 1, time: 1} in that order; shells and other drivers store the 1s as doubles
+fn is_legacy_unique_keys(keys: &Document) -> bool {
+    let ascending = |value: &Bson| match value {
+        Bson::Int32(n) => *n == 1,
+        Bson::Int64(n) => *n == 1,
+        Bson::Double(n) => *n == 1.0,
+        _ => false,
+    };
+    let keys: Vec<(&String, &Bson)> = keys.iter().collect();
+    matches!(keys.as_slice(), [(ip, a), (time, b)]
+        if *ip == "ip" && *time == "time" && ascending(a) && ascending(b))
+}
+
+async fn backfill_rollups(db: &Database) -> anyhow::Result<()> {
+    let logents_coll: Collection<LogEntry> = db.collection("logentries");
+    let first_options = FindOneOptions::builder().sort(doc! {"time": 1}).build();
+    let last_options = FindOneOptions::builder().sort(doc! {"time": -1}).build();
+    let first = logents_coll.find_one(None, first_options).await?;
+    let last = logents_coll.find_one(None, last_options).await?;
+    let (Some(first), Some(last)) = (first, last) else {
+        return Ok(());
+    };
+    // * one day at a time to bound memory use
+    let mut day = first.time.to_chrono();
+    let end = last.time.to_chrono();
+    while day <= end {
+        let next = day + chrono::Duration::days(1);
+        let date_range = DateRange {
+            start: day.into(),
+            end: next.into(),
+        };
+        rollup::update_rollups(db, &date_range).await?;
+        day = next;
+    }
+    Ok(())
+}
+
+// * one update per distinct ip; ips that do not parse are left without a key
+async fn backfill_ip_keys(db: &Database) -> anyhow::Result<()> {
+    for coll_name in ["logentries", "hostdata"] {
+        let coll: Collection<Document> = db.collection(coll_name);
+        let pipeline = vec![
+            doc! {"$match": doc! {"ip_key": doc! {"$exists": false}}},
+            doc! {"$group": doc! {"_id": "$ip"}},
+        ];
+        let mut curs = coll.aggregate(pipeline, None).await?;
+        while let Some(group) = curs.try_next().await? {
+            let Ok(ip) = group.get_str("_id") else {
+                continue;
+            };
+            if let Some(key) = cidr::ip_key(ip) {
+                coll.update_many(
+                    doc! {"ip": ip, "ip_key": doc! {"$exists": false}},
+                    doc! {"$set": doc! {"ip_key": key}},
+                    None,
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn apply(migration: &Migration, db: &Database) -> anyhow::Result<()> {
+    match migration.version {
+        1 => rebuild_logentries_unique_index(db).await,
+        2 => backfill_rollups(db).await,
+        3 => backfill_ip_keys(db).await,
+        v => bail!("No migration step for schema version {v}"),
+    }
+}
+
+pub async fn migrate(config: &Config) -> anyhow::Result<()> {
+    let (db, _, _) = crate::setup_db(config).await?;
+    let current = get_schema_version(&db).await?;
+    let steps = pending(current);
+    if steps.is_empty() {
+        println!("Schema is up to date at version {current}");
+        return Ok(());
+    }
+    for migration in steps {
+        info!(
+            version = migration.version,
+            "Migrating: {}", migration.description
+        );
+        apply(migration, &db).await?;
+        // * record each step so an interrupted run resumes where it stopped
+        set_schema_version(&db, migration.version).await?;
+    }
+    // * setup_db again so any index dropped by a migration is rebuilt
+    crate::setup_db(config).await?;
+    println!("Schema is now at version {}", latest_version());
+    Ok(())
+}
+
+pub async fn status(config: &Config) -> anyhow::Result<()> {
+    let (db, _, _) = crate::setup_db(config).await?;
+    let current = get_schema_version(&db).await?;
+    println!(
+        "{}: {} (latest {})",
+        style("Schema version").red(),
+        current,
+        latest_version()
+    );
+    let steps = pending(current);
+    if steps.is_empty() {
+        println!("No pending migrations");
+    }
+    for migration in steps {
+        println!(
+            "{} {}: {}",
+            style("Pending").red(),
+            style(migration.version).yellow(),
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_ordered() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<i32> = (1..=MIGRATIONS.len() as i32).collect();
+        assert_eq!(versions, expected);
+    }
+
+    #[test]
+    fn legacy_index_keys_in_any_number_type() {
+        assert!(is_legacy_unique_keys(&doc! {"ip": 1, "time": 1}));
+        assert!(is_legacy_unique_keys(&doc! {"ip": 1.0, "time": 1_i64}));
+        assert!(!is_legacy_unique_keys(&doc! {"ip": 1, "time": -1}));
+        assert!(!is_legacy_unique_keys(
+            &doc! {"ip": 1, "time": 1, "method": 1}
+        ));
+    }
+
+    #[test]
+    fn pending_migrations() {
+        assert_eq!(pending(0).len(), MIGRATIONS.len());
+        assert!(pending(latest_version()).is_empty());
+    }
+}
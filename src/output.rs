@@ -0,0 +1,367 @@
+// * machine readable output of command results; diagnostics never go to stdout
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+
+use crate::log_entries::LogEntry;
+use crate::query::{HostSummary, MatchedIp};
+use crate::HostData;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    // * human readable, colored when stdout is a terminal
+    Table,
+    Json,
+    Ndjson,
+    Csv,
+}
+
+// * color only makes sense on a terminal; NO_COLOR turns it off there too
+pub fn init_colors() {
+    let enabled = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    console::set_colors_enabled(enabled);
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryRecord {
+    // * RFC 3339, UTC
+    pub time: String,
+    pub method: String,
+    pub path: String,
+    pub code: u32,
+    pub nbytes: u32,
+    pub referrer: String,
+    pub ua: String,
+}
+
+impl From<&LogEntry> for EntryRecord {
+    fn from(le: &LogEntry) -> EntryRecord {
+        EntryRecord {
+            time: le.time.to_chrono().to_rfc3339(),
+            // * just the verb; the path has its own field
+            method: le
+                .method
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_string(),
+            path: le.path().to_string(),
+            code: le.code,
+            nbytes: le.nbytes,
+            referrer: le.referrer.clone(),
+            ua: le.ua.clone(),
+        }
+    }
+}
+
+// * one host in search results; geo fields are empty for ips not yet looked up
+#[derive(Debug, Serialize)]
+pub struct HostRecord {
+    pub ip: String,
+    // * network or domain with --group-by-prefix or --group-by-domain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    // * traffic in the searched range; a host report carries its own totals instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<i64>,
+    // * RFC 3339, UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+    pub country: String,
+    pub organization: String,
+    pub state_prov: String,
+    pub city: String,
+    pub isp: String,
+    pub ptr_records: Vec<String>,
+    // * absent rather than empty when entries were not asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<EntryRecord>>,
+    // * only with search --summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<HostSummary>,
+}
+
+impl HostRecord {
+    pub fn new(ip: &str, hostdata: Option<&HostData>, entries: Option<Vec<EntryRecord>>) -> Self {
+        match hostdata {
+            Some(hd) => HostRecord {
+                ip: ip.to_string(),
+                group: None,
+                requests: None,
+                bytes: None,
+                first_seen: None,
+                last_seen: None,
+                country: hd.geodata.country_name.clone(),
+                organization: hd.geodata.organization.clone(),
+                state_prov: hd.geodata.state_prov.clone(),
+                city: hd.geodata.city.clone(),
+                isp: hd.geodata.isp.clone(),
+                ptr_records: hd.ptr_records.clone(),
+                entries,
+                summary: None,
+            },
+            None => HostRecord {
+                ip: ip.to_string(),
+                group: None,
+                requests: None,
+                bytes: None,
+                first_seen: None,
+                last_seen: None,
+                country: String::new(),
+                organization: String::new(),
+                state_prov: String::new(),
+                city: String::new(),
+                isp: String::new(),
+                ptr_records: vec![],
+                entries,
+                summary: None,
+            },
+        }
+    }
+
+    pub fn with_traffic(self, matched: &MatchedIp) -> Self {
+        HostRecord {
+            requests: Some(matched.requests),
+            bytes: Some(matched.bytes),
+            first_seen: Some(matched.first_seen.to_chrono().to_rfc3339()),
+            last_seen: Some(matched.last_seen.to_chrono().to_rfc3339()),
+            ..self
+        }
+    }
+
+    fn host_cells(&self) -> Vec<String> {
+        let cell = |value: &Option<String>| value.clone().unwrap_or_default();
+        let number = |value: Option<i64>| value.map(|n| n.to_string()).unwrap_or_default();
+        vec![
+            self.ip.clone(),
+            cell(&self.group),
+            number(self.requests),
+            number(self.bytes),
+            cell(&self.first_seen),
+            cell(&self.last_seen),
+            self.country.clone(),
+            self.organization.clone(),
+            self.state_prov.clone(),
+            self.city.clone(),
+            self.isp.clone(),
+            self.ptr_records.join(";"),
+        ]
+    }
+
+    fn summary_cells(&self) -> Vec<String> {
+        match &self.summary {
+            // * totals are in the host columns already
+            Some(summary) => vec![
+                pairs(summary.status.iter()),
+                pairs(summary.top_paths.iter().map(|kc| (&kc.key, &kc.count))),
+                pairs(summary.user_agents.iter().map(|kc| (&kc.key, &kc.count))),
+            ],
+            None => vec![String::new(); SUMMARY_COLUMNS.len()],
+        }
+    }
+}
+
+const HOST_COLUMNS: [&str; 12] = [
+    "ip",
+    "group",
+    "requests",
+    "bytes",
+    "first_seen",
+    "last_seen",
+    "country",
+    "organization",
+    "state_prov",
+    "city",
+    "isp",
+    "ptr_records",
+];
+// * csv cell for a list of counts, as "key:count" pairs joined with ';'
+fn pairs<'a>(key_counts: impl Iterator<Item = (&'a String, &'a i64)>) -> String {
+    key_counts
+        .map(|(key, count)| format!("{key}:{count}"))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+const SUMMARY_COLUMNS: [&str; 3] = ["status", "top_paths", "user_agents"];
+const ENTRY_COLUMNS: [&str; 7] = ["time", "method", "path", "code", "nbytes", "referrer", "ua"];
+
+pub fn write_json<W: Write, T: Serialize>(out: &mut W, items: &[T]) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, items)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+pub fn write_ndjson<W: Write, T: Serialize>(out: &mut W, items: &[T]) -> anyhow::Result<()> {
+    for item in items {
+        serde_json::to_writer(&mut *out, item)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub fn write_csv<W: Write>(
+    out: &mut W,
+    header: &[&str],
+    rows: impl IntoIterator<Item = Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// * csv has no nesting, so with entries there is one row per entry with the host repeated;
+// * a host without entries gets one row with the entry cells empty
+pub fn write_hosts<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    hosts: &[HostRecord],
+    with_entries: bool,
+) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Json => write_json(out, hosts),
+        OutputFormat::Ndjson => write_ndjson(out, hosts),
+        OutputFormat::Csv if with_entries => {
+            let header: Vec<&str> = HOST_COLUMNS.iter().chain(&ENTRY_COLUMNS).copied().collect();
+            let rows = hosts.iter().flat_map(|host| {
+                let entries = host.entries.as_deref().unwrap_or_default();
+                if entries.is_empty() {
+                    let mut row = host.host_cells();
+                    row.extend(vec![String::new(); ENTRY_COLUMNS.len()]);
+                    return vec![row];
+                }
+                entries
+                    .iter()
+                    .map(|entry| {
+                        let mut row = host.host_cells();
+                        row.extend([
+                            entry.time.clone(),
+                            entry.method.clone(),
+                            entry.path.clone(),
+                            entry.code.to_string(),
+                            entry.nbytes.to_string(),
+                            entry.referrer.clone(),
+                            entry.ua.clone(),
+                        ]);
+                        row
+                    })
+                    .collect()
+            });
+            write_csv(out, &header, rows)
+        }
+        OutputFormat::Csv if hosts.iter().any(|host| host.summary.is_some()) => {
+            let header: Vec<&str> = HOST_COLUMNS
+                .iter()
+                .chain(&SUMMARY_COLUMNS)
+                .copied()
+                .collect();
+            let rows = hosts.iter().map(|host| {
+                let mut row = host.host_cells();
+                row.extend(host.summary_cells());
+                row
+            });
+            write_csv(out, &header, rows)
+        }
+        OutputFormat::Csv => {
+            write_csv(out, &HOST_COLUMNS, hosts.iter().map(HostRecord::host_cells))
+        }
+        OutputFormat::Table => unreachable!("table output is written by the command itself"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(entries: Option<Vec<EntryRecord>>) -> HostRecord {
+        let mut host = HostRecord::new("192.0.2.1", None, entries);
+        host.group = Some("192.0.2.0/24".to_string());
+        host.requests = Some(2);
+        host.bytes = Some(324);
+        host.first_seen = Some("2024-12-09T10:00:00+00:00".to_string());
+        host.last_seen = Some("2024-12-09T10:00:00+00:00".to_string());
+        host.country = "Germany".to_string();
+        host.ptr_records = vec!["a.example.".to_string(), "b.example.".to_string()];
+        host
+    }
+
+    fn entry() -> EntryRecord {
+        EntryRecord {
+            time: "2024-12-09T10:00:00+00:00".to_string(),
+            method: "GET".to_string(),
+            path: "/wp-login.php".to_string(),
+            code: 404,
+            nbytes: 162,
+            referrer: "-".to_string(),
+            ua: "curl/8.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn json_schema_is_stable() {
+        let mut out = vec![];
+        write_hosts(&mut out, OutputFormat::Ndjson, &[host(None)], false).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let mut keys: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        let mut expected = HOST_COLUMNS;
+        expected.sort();
+        assert_eq!(keys, expected);
+        let mut out = vec![];
+        write_hosts(
+            &mut out,
+            OutputFormat::Json,
+            &[host(Some(vec![entry()]))],
+            true,
+        )
+        .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value[0]["entries"][0]["code"], 404);
+    }
+
+    #[test]
+    fn csv_flattens_summary() {
+        let mut host = host(None);
+        host.summary = Some(HostSummary {
+            requests: 3,
+            first_seen: "2024-12-09T10:00:00+00:00".to_string(),
+            last_seen: "2024-12-09T11:00:00+00:00".to_string(),
+            status: [("200".to_string(), 1), ("404".to_string(), 2)].into(),
+            top_paths: vec![],
+            user_agents: vec![],
+        });
+        let mut out = vec![];
+        write_hosts(&mut out, OutputFormat::Csv, &[host], false).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].ends_with(",ptr_records,status,top_paths,user_agents"));
+        assert!(lines[1].contains(",2,324,") && lines[1].contains("200:1;404:2"));
+    }
+
+    #[test]
+    fn csv_repeats_host_per_entry() {
+        let mut out = vec![];
+        let hosts = [host(Some(vec![entry(), entry()])), host(Some(vec![]))];
+        write_hosts(&mut out, OutputFormat::Csv, &hosts, true).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("ip,group,requests,bytes,"));
+        assert!(lines[1].contains("192.0.2.0/24,2,324,"));
+        assert!(lines[1].contains("a.example.;b.example."));
+        // * the host without entries still has its row
+        assert!(lines[3].ends_with("a.example.;b.example.,,,,,,,"));
+    }
+}
@@ -0,0 +1,134 @@
+// * enforce the retention policy by removing old log entries and orphaned host data
+use anyhow::anyhow;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Document};
+
+use crate::{timespec, Config, HostDataColl, LogEntryColl, RetentionConfig};
+
+// * orphaned ips are deleted this many at a time, keeping each $in well under the bson limit
+const DELETE_CHUNK: usize = 1000;
+
+// * pipeline selecting hostdata ips with no log entries at or after cutoff
+fn orphan_pipeline(cutoff: bson::DateTime) -> Vec<Document> {
+    vec![
+        doc! {
+            "$lookup": doc! {
+                "from": "logentries",
+                "let": doc! {"host_ip": "$ip"},
+                "pipeline": [
+                    doc! {"$match": doc! {
+                        "$expr": doc! {"$eq": ["$ip", "$$host_ip"]},
+                        "time": doc! {"$gte": cutoff}
+                    }},
+                    doc! {"$limit": 1},
+                    doc! {"$project": doc! {"_id": 1}},
+                ],
+                "as": "recent"
+            }
+        },
+        doc! {"$match": doc! {"recent": doc! {"$size": 0}}},
+        doc! {"$project": doc! {"_id": 0, "ip": 1}},
+    ]
+}
+
+async fn find_orphaned_ips(
+    host_data_coll: &HostDataColl,
+    cutoff: bson::DateTime,
+) -> anyhow::Result<Vec<String>> {
+    let curs = host_data_coll
+        .aggregate(orphan_pipeline(cutoff), None)
+        .await?;
+    let docs = curs.try_collect::<Vec<Document>>().await?;
+    let ips = docs
+        .iter()
+        .filter_map(|doc| doc.get_str("ip").ok().map(String::from))
+        .collect();
+    Ok(ips)
+}
+
+// * the age cutoff comes from the command line if given, else from the config
+fn cutoff_from(
+    older_than: &Option<String>,
+    retention: &RetentionConfig,
+) -> anyhow::Result<bson::DateTime> {
+    let max_age = match (older_than, retention.logentry_days) {
+        (Some(spec), _) => timespec::parse_duration(spec)?,
+        (None, Some(days)) => chrono::Duration::try_days(days.into())
+            .ok_or(anyhow!("retention.logentry_days is too large"))?,
+        (None, None) => {
+            return Err(anyhow!(
+                "No retention period: pass --older-than or set retention.logentry_days in config"
+            ))
+        }
+    };
+    let cutoff = chrono::Utc::now()
+        .checked_sub_signed(max_age)
+        .ok_or(anyhow!(
+            "Retention period reaches before the earliest representable time"
+        ))?;
+    Ok(cutoff.into())
+}
+
+pub async fn prune(
+    older_than: &Option<String>,
+    dry_run: &bool,
+    orphans: &bool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let cutoff = cutoff_from(older_than, &config.retention)?;
+    let (_, host_data_coll, logents_coll): (_, HostDataColl, LogEntryColl) =
+        crate::setup_db(config).await?;
+    let old_entries = doc! {"time": doc! {"$lt": cutoff}};
+    let prune_orphans = *orphans || config.retention.prune_orphans;
+
+    if *dry_run {
+        let n_entries = logents_coll.count_documents(old_entries, None).await?;
+        println!("Would remove {n_entries} log entries older than {cutoff}");
+        if prune_orphans {
+            let orphaned = find_orphaned_ips(&host_data_coll, cutoff).await?;
+            println!("Would remove hostdata for {} ips", orphaned.len());
+        }
+        return Ok(());
+    }
+
+    let result = logents_coll.delete_many(old_entries, None).await?;
+    println!(
+        "Removed {} log entries older than {cutoff}",
+        result.deleted_count
+    );
+    if prune_orphans {
+        let orphaned = find_orphaned_ips(&host_data_coll, cutoff).await?;
+        let mut deleted = 0;
+        for chunk in orphaned.chunks(DELETE_CHUNK) {
+            let result = host_data_coll
+                .delete_many(doc! {"ip": doc! {"$in": chunk}}, None)
+                .await?;
+            deleted += result.deleted_count;
+        }
+        println!("Removed hostdata for {deleted} ips");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoff_needs_a_period() {
+        let retention = RetentionConfig::default();
+        assert!(cutoff_from(&None, &retention).is_err());
+        assert!(cutoff_from(&Some("99999999999999d".to_string()), &retention).is_err());
+    }
+
+    #[test]
+    fn cutoff_prefers_cli_over_config() {
+        let retention = RetentionConfig {
+            logentry_days: Some(90),
+            ..Default::default()
+        };
+        let from_cli = cutoff_from(&Some("1d".to_string()), &retention).unwrap();
+        let from_config = cutoff_from(&None, &retention).unwrap();
+        assert!(from_cli > from_config);
+    }
+}
@@ -1,15 +1,24 @@
 use super::Logdate;
+use crate::cidr;
 use crate::log_entries::LogEntry;
+use crate::rollup::KeyCount;
+use crate::stats;
+use anyhow::{anyhow, bail};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use bson;
 use bson::Document;
 use bson::{Bson, DateTime};
 use futures::stream::{StreamExt, TryStreamExt};
+use ipnet::IpNet;
 use mongodb::bson::doc;
 use mongodb::bson::Regex;
-use mongodb::options::FindOptions;
-use mongodb::{Collection, Cursor};
+use mongodb::{Collection, Cursor, IndexModel};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use tracing::debug;
 
 type IpsInDaterange = Vec<String>;
 
@@ -19,18 +28,471 @@ pub struct DateRange {
     pub end: DateTime,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct CountryWithIps {
-    #[serde(alias = "_id")]
-    pub country: String,
-    pub ips: Vec<String>,
+// * ranges of http status codes, from e.g. "4xx", "404,444" or "404,5xx"
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusFilter(Vec<(u32, u32)>);
+
+impl FromStr for StatusFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<StatusFilter> {
+        let ranges = spec
+            .split(',')
+            .map(|part| {
+                let part = part.trim().to_ascii_lowercase();
+                match part.strip_suffix("xx") {
+                    Some(class) => match class.parse::<u32>() {
+                        Ok(class @ 1..=5) => Ok((class * 100, class * 100 + 99)),
+                        _ => bail!("Bad status class {part:?}; expected 1xx to 5xx"),
+                    },
+                    None => match part.parse::<u32>() {
+                        Ok(code @ 100..=599) => Ok((code, code)),
+                        _ => bail!(
+                            "Bad status {part:?}; expected a code like 404 or a class like 4xx"
+                        ),
+                    },
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(StatusFilter(ranges))
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct OrgWithIps {
-    #[serde(alias = "_id")]
-    pub org: String,
-    pub ips: Vec<String>,
+impl StatusFilter {
+    fn condition(&self) -> Document {
+        let ranges: Vec<Document> = self
+            .0
+            .iter()
+            .map(|(low, high)| doc! {"code": doc! {"$gte": low, "$lte": high}})
+            .collect();
+        doc! {"$or": ranges}
+    }
+}
+
+// * filters on individual log entries, applied before entries are grouped by host
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct EntryFilters {
+    /// http status codes or classes, e.g. 4xx or 404,444
+    #[clap(long)]
+    pub status: Option<StatusFilter>,
+
+    /// request methods, e.g. POST or GET,HEAD
+    #[clap(long, value_delimiter = ',')]
+    pub method: Vec<String>,
+
+    /// regex on the request path, e.g. '\.php$'
+    #[clap(long)]
+    pub path_regex: Option<String>,
+
+    /// regex on the user agent, e.g. 'curl|python'
+    #[clap(long)]
+    pub ua_regex: Option<String>,
+
+    /// regex on the referrer
+    #[clap(long)]
+    pub referrer_regex: Option<String>,
+}
+
+impl EntryFilters {
+    // * the method field holds the whole request line, e.g. "GET /path HTTP/1.1"
+    // * the path filter is not among them; see path_stages
+    pub(crate) fn conditions(&self) -> Vec<Document> {
+        let mut conditions = vec![];
+        if let Some(status) = &self.status {
+            conditions.push(status.condition());
+        }
+        if !self.method.is_empty() {
+            let methods: Vec<String> = self
+                .method
+                .iter()
+                .map(|method| regex::escape(&method.to_ascii_uppercase()))
+                .collect();
+            conditions.push(doc! {"method": regex(&format!("^(?:{}) ", methods.join("|")))});
+        }
+        if let Some(ua) = &self.ua_regex {
+            conditions.push(doc! {"ua": regex(ua)});
+        }
+        if let Some(referrer) = &self.referrer_regex {
+            conditions.push(doc! {"referrer": regex(referrer)});
+        }
+        conditions
+    }
+
+    // * the path is split out of the request line into a temporary field for the regex
+    fn path_stages(&self) -> Vec<Document> {
+        match &self.path_regex {
+            Some(path) => vec![
+                doc! {"$addFields": doc! {"path": stats::path()}},
+                doc! {"$match": doc! {"path": regex(path)}},
+                doc! {"$unset": "path"},
+            ],
+            None => vec![],
+        }
+    }
+}
+
+// * search filters; all given filters must hold, each host filter has a negated form
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct SearchFilters {
+    #[clap(flatten)]
+    pub entries: EntryFilters,
+
+    /// regex search by IP address
+    #[clap(long, short)]
+    pub ip: Option<String>,
+
+    /// exclude IP addresses matching regex
+    #[clap(long)]
+    pub not_ip: Option<String>,
+
+    /// IP addresses in these networks, e.g. 45.148.10.0/24 or 2a06:4880::/32
+    #[clap(long, value_delimiter = ',')]
+    pub cidr: Vec<IpNet>,
+
+    /// exclude IP addresses in these networks
+    #[clap(long, value_delimiter = ',')]
+    pub not_cidr: Vec<IpNet>,
+
+    /// search by country, by full name as looked up, e.g. "United States" (not a code like US);
+    /// with no countries, any known country
+    #[clap(long, short, num_args(0..), value_parser = country_name)]
+    pub country: Option<Vec<String>>,
+
+    /// exclude these countries, by full name as with --country
+    #[clap(long, num_args(1..), value_parser = country_name)]
+    pub not_country: Vec<String>,
+
+    /// regex search by organization
+    #[clap(long, short)]
+    pub org: Option<String>,
+
+    /// exclude organizations matching regex
+    #[clap(long)]
+    pub not_org: Option<String>,
+
+    /// regex search by hostname (PTR record), e.g. '\.amazonaws\.com$'
+    #[clap(long)]
+    pub host_regex: Option<String>,
+
+    /// exclude IP addresses with a hostname matching regex
+    #[clap(long)]
+    pub not_host_regex: Option<String>,
+}
+
+fn regex(pattern: &str) -> Regex {
+    Regex {
+        pattern: pattern.to_string(),
+        options: String::new(),
+    }
+}
+
+impl SearchFilters {
+    pub fn is_empty(&self) -> bool {
+        self.conditions().is_empty()
+    }
+
+    // * one $match condition per filter given
+    fn conditions(&self) -> Vec<Document> {
+        let mut conditions = self.ip_conditions();
+        conditions.extend(self.host_conditions());
+        conditions
+    }
+
+    // * conditions on the ip alone, which hold for log entries before they are grouped
+    fn ip_conditions(&self) -> Vec<Document> {
+        let mut conditions = vec![];
+        if let Some(ip) = &self.ip {
+            conditions.push(doc! {"ip": regex(ip)});
+        }
+        if let Some(ip) = &self.not_ip {
+            conditions.push(doc! {"ip": doc! {"$not": regex(ip)}});
+        }
+        if !self.cidr.is_empty() {
+            conditions.push(cidr::in_any(&self.cidr));
+        }
+        if !self.not_cidr.is_empty() {
+            conditions.push(doc! {"$nor": [cidr::in_any(&self.not_cidr)]});
+        }
+        conditions
+    }
+
+    // * conditions on hosts grouped from log entries and joined with their hostdata
+    fn host_conditions(&self) -> Vec<Document> {
+        let mut conditions = vec![];
+        match &self.country {
+            Some(countries) if countries.is_empty() => {
+                conditions.push(doc! {"country": doc! {"$type": "string"}})
+            }
+            Some(countries) => conditions.push(doc! {"country": doc! {"$in": countries}}),
+            None => (),
+        }
+        if !self.not_country.is_empty() {
+            conditions.push(doc! {"country": doc! {"$nin": &self.not_country}});
+        }
+        if let Some(org) = &self.org {
+            conditions.push(doc! {"org": regex(org)});
+        }
+        if let Some(org) = &self.not_org {
+            conditions.push(doc! {"org": doc! {"$not": regex(org)}});
+        }
+        // * an ip matches if any of its ptr records does
+        if let Some(host) = &self.host_regex {
+            conditions.push(doc! {"ptr_records": regex(host)});
+        }
+        if let Some(host) = &self.not_host_regex {
+            conditions.push(doc! {"ptr_records": doc! {"$not": regex(host)}});
+        }
+        conditions
+    }
+
+    // * ips with their country, org and traffic, restricted to those passing every filter
+    fn pipeline(&self) -> Vec<Document> {
+        let mut pipeline = vec![];
+        // * before grouping, so the ip and ip_key indexes are used
+        let ip_conditions = self.ip_conditions();
+        if !ip_conditions.is_empty() {
+            pipeline.push(doc! {"$match": doc! {"$and": ip_conditions}});
+        }
+        pipeline.extend([
+            doc! {"$group": doc! {
+                "_id": "$ip",
+                "ip_key": doc! {"$first": "$ip_key"},
+                "requests": doc! {"$sum": 1},
+                "bytes": doc! {"$sum": "$nbytes"},
+                "first_seen": doc! {"$min": "$time"},
+                "last_seen": doc! {"$max": "$time"},
+            }},
+            doc! {
+                "$lookup": doc! {
+                    "as": "hostdata",
+                    "from": "hostdata",
+                    "foreignField": "ip",
+                    "localField": "_id"
+                }
+            },
+            doc! {
+                "$project": doc! {
+                    "_id": 0,
+                    "ip": "$_id",
+                    "ip_key": 1,
+                    "requests": 1,
+                    "bytes": 1,
+                    "first_seen": 1,
+                    "last_seen": 1,
+                    "country": doc! {"$first": "$hostdata.geodata.country_name"},
+                    "org": doc! {"$first": "$hostdata.geodata.organization"},
+                    // * stored names end in the root dot; without it, patterns anchored
+                    // * at the end such as '\.com$' match
+                    "ptr_records": doc! {"$map": doc! {
+                        "input": doc! {"$first": "$hostdata.ptr_records"},
+                        "in": doc! {"$rtrim": doc! {"input": "$$this", "chars": PTR_ROOT}},
+                    }},
+                }
+            },
+        ]);
+        let host_conditions = self.host_conditions();
+        if !host_conditions.is_empty() {
+            pipeline.push(doc! {"$match": doc! {"$and": host_conditions}});
+        }
+        pipeline
+    }
+}
+
+// * only full names are stored, so a country code would silently match nothing
+fn country_name(arg: &str) -> anyhow::Result<String> {
+    if arg.len() == 2 && arg.chars().all(|c| c.is_ascii_alphabetic()) {
+        bail!("{arg:?} looks like a country code; use the full name, e.g. \"United States\"");
+    }
+    Ok(arg.to_string())
+}
+
+// * trimmed from the end of stored ptr names before host regexes see them
+const PTR_ROOT: &str = ".";
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SortKey {
+    // * most requests first
+    Requests,
+    // * most bytes first
+    Bytes,
+    // * earliest first
+    FirstSeen,
+    // * latest first
+    LastSeen,
+    // * by address, IPv4 before global IPv6
+    Ip,
+    Country,
+}
+
+impl SortKey {
+    fn name(&self) -> &'static str {
+        match self {
+            SortKey::Requests => "requests",
+            SortKey::Bytes => "bytes",
+            SortKey::FirstSeen => "first-seen",
+            SortKey::LastSeen => "last-seen",
+            SortKey::Ip => "ip",
+            SortKey::Country => "country",
+        }
+    }
+
+    // * 1 for ascending, -1 for descending
+    fn direction(&self) -> i32 {
+        match self {
+            SortKey::Requests | SortKey::Bytes | SortKey::LastSeen => -1,
+            SortKey::FirstSeen | SortKey::Ip | SortKey::Country => 1,
+        }
+    }
+
+    // * condition for hosts that come after the one with sort key value and ip
+    fn after(&self, value: Bson, ip: &str) -> Document {
+        let beyond = if self.direction() < 0 { "$lt" } else { "$gt" };
+        doc! {"$or": [
+            doc! {"sort_key": doc! {beyond: value.clone()}},
+            doc! {"sort_key": value, "ip": doc! {"$gt": ip}},
+        ]}
+    }
+
+    // * the value sorted on; never null, so keyset comparisons always apply
+    fn expression(&self) -> Bson {
+        match self {
+            SortKey::Requests => "$requests".into(),
+            SortKey::Bytes => "$bytes".into(),
+            SortKey::FirstSeen => "$first_seen".into(),
+            SortKey::LastSeen => "$last_seen".into(),
+            // * ip_key orders by address; entries stored before schema v3 have none, and an
+            // * empty key, still binary so keyset comparisons hold, puts them first
+            SortKey::Ip => doc! {"$ifNull": ["$ip_key", cidr::no_key()]}.into(),
+            SortKey::Country => doc! {"$ifNull": ["$country", ""]}.into(),
+        }
+    }
+}
+
+// * ordering and paging of search results; pages are keyed on the last host shown, so
+// * they stay consistent while new entries arrive
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct PageArgs {
+    /// order of hosts; without it hosts are ordered by country, org and ip
+    #[clap(long, value_enum)]
+    pub sort: Option<SortKey>,
+
+    /// show at most this many hosts
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub limit: Option<usize>,
+
+    /// show at most this many entries per host, earliest first
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_entries: Option<usize>,
+
+    /// continue after the last page; the token is printed after a page that was cut off by --limit
+    #[clap(long)]
+    pub page_token: Option<String>,
+}
+
+impl PageArgs {
+    // * with paging the order must be well defined, so it defaults to ip
+    fn sort_key(&self) -> Option<SortKey> {
+        match self.sort {
+            Some(key) => Some(key),
+            None if self.limit.is_some() || self.page_token.is_some() => Some(SortKey::Ip),
+            None => None,
+        }
+    }
+
+    // * whether hosts should be shown in the order found rather than re-sorted for display
+    pub fn is_ordered(&self) -> bool {
+        self.sort_key().is_some()
+    }
+
+    fn stages(&self) -> anyhow::Result<Vec<Document>> {
+        let Some(key) = self.sort_key() else {
+            return Ok(vec![doc! {"$sort": doc! {"country": 1, "org": 1, "ip": 1}}]);
+        };
+        let mut stages = vec![doc! {"$addFields": doc! {"sort_key": key.expression()}}];
+        if let Some(token) = &self.page_token {
+            let (value, ip) = decode_page_token(token, key)?;
+            stages.push(doc! {"$match": key.after(value, &ip)});
+        }
+        stages.push(doc! {"$sort": doc! {"sort_key": key.direction(), "ip": 1}});
+        if let Some(limit) = self.limit {
+            // * one extra to learn whether there is a next page
+            stages.push(doc! {"$limit": limit as i64 + 1});
+        }
+        Ok(stages)
+    }
+}
+
+fn encode_page_token(key: SortKey, value: &Bson, ip: &str) -> anyhow::Result<String> {
+    let token = doc! {"sort": key.name(), "after": value.clone(), "ip": ip};
+    Ok(URL_SAFE_NO_PAD.encode(bson::to_vec(&token)?))
+}
+
+fn decode_page_token(token: &str, key: SortKey) -> anyhow::Result<(Bson, String)> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(token.trim())
+        .map_err(|_| anyhow!("Bad page token"))?;
+    let token: Document = bson::from_slice(&bytes).map_err(|_| anyhow!("Bad page token"))?;
+    let sort = token
+        .get_str("sort")
+        .map_err(|_| anyhow!("Bad page token"))?;
+    if sort != key.name() {
+        bail!("Page token is for --sort {sort}, not {}", key.name());
+    }
+    match (token.get("after"), token.get_str("ip")) {
+        (Some(value), Ok(ip)) => Ok((value.clone(), ip.to_string())),
+        _ => bail!("Bad page token"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MatchedIp {
+    pub ip: String,
+    pub requests: i64,
+    pub bytes: i64,
+    pub first_seen: DateTime,
+    pub last_seen: DateTime,
+    // * None when the ip has no hostdata yet
+    pub country: Option<String>,
+    pub org: Option<String>,
+    pub ptr_records: Option<Vec<String>>,
+    #[serde(default)]
+    sort_key: Bson,
+}
+
+// * one page of matched ips, with the token for the next page if --limit cut it off
+pub struct MatchedPage {
+    pub matched: Vec<MatchedIp>,
+    pub next_page_token: Option<String>,
+}
+
+// * must call make_current_le_coll before calling this!
+pub async fn find_matching_ips(
+    current_logentries_coll: &Collection<LogEntry>,
+    filters: &SearchFilters,
+    page: &PageArgs,
+) -> anyhow::Result<MatchedPage> {
+    let mut pipeline = filters.pipeline();
+    pipeline.extend(page.stages()?);
+    debug!("Search pipeline is {:?}", pipeline);
+    let curs = current_logentries_coll.aggregate(pipeline, None).await?;
+    let docs = curs.try_collect::<Vec<Document>>().await?;
+    let mut matched: Vec<MatchedIp> = vec![];
+    for doc in docs {
+        matched.push(bson::from_document(doc)?);
+    }
+    let mut next_page_token = None;
+    if let (Some(limit), Some(key)) = (page.limit, page.sort_key()) {
+        if matched.len() > limit {
+            matched.truncate(limit);
+            if let Some(last) = matched.last() {
+                next_page_token = Some(encode_page_token(key, &last.sort_key, &last.ip)?);
+            }
+        }
+    }
+    Ok(MatchedPage {
+        matched,
+        next_page_token,
+    })
 }
 
 pub fn time_str_to_bson(
@@ -49,49 +511,24 @@ pub fn time_str_to_daterange(start_str: &str, end_str: &str) -> anyhow::Result<D
     Ok(DateRange { start: s, end: e })
 }
 
+// * one {_id: ip, count} per ip with entries in date_range
+fn ips_in_daterange_pipeline(date_range: &DateRange) -> Vec<Document> {
+    let time_filter = doc! {"$match": {"time": {"$gte": date_range.start, "$lt": date_range.end}}};
+    let grouper = doc! {"$group": {"_id": "$ip", "count": {"$sum": 1}}};
+    vec![time_filter, grouper]
+}
+
 async fn get_unique_ips_in_daterange(
     coll: &Collection<LogEntry>,
     date_range: &DateRange,
 ) -> anyhow::Result<Cursor<Document>> {
-    let time_filter = doc! {"$match": {"time": {"$gte": date_range.start, "$lt": date_range.end}}};
-    let grouper = doc! {"$group": {"_id": "$ip"}};
-    let sorter = doc! {"$sort": {"_id": 1}};
-    let pipeline = vec![time_filter, grouper, sorter];
+    let mut pipeline = ips_in_daterange_pipeline(date_range);
+    pipeline.push(doc! {"$sort": {"_id": 1}});
     coll.aggregate(pipeline, None)
         .await
         .map_err(anyhow::Error::msg)
 }
 
-#[derive(Deserialize)]
-struct ProjectedLogEntry {
-    ip: String,
-}
-
-pub async fn find_ips_matching_regex(
-    current_logentries_coll: &Collection<LogEntry>,
-    pattern: &String,
-) -> anyhow::Result<Vec<String>> {
-    println!("The pattern is {}", pattern);
-    let re = Regex {
-        pattern: pattern.clone(),
-        options: String::new(),
-    };
-    let filter = doc! {"ip": doc! {"$regex": re}};
-    // let filter = doc! {"ip": "94.102.61.7"};
-    let proj = doc! {"ip": 1};
-    let options = FindOptions::builder().projection(proj).build();
-    let mut curs = current_logentries_coll
-        .clone_with_type::<ProjectedLogEntry>()
-        .find(filter, options)
-        .await?;
-    // * use HashSet to ensure uniqueness, then convert to vec for compatibility
-    let mut ips: HashSet<String> = HashSet::new();
-    while let Some(projected_doc) = curs.try_next().await? {
-        ips.insert(projected_doc.ip);
-    }
-    Ok(ips.into_iter().collect())
-}
-
 pub async fn find_ips_in_daterange(
     coll: &Collection<LogEntry>,
     date_range: &DateRange,
@@ -112,128 +549,445 @@ pub async fn find_ips_in_daterange(
     Ok(ips_in_daterange)
 }
 
-pub async fn make_current_le_coll(
+// * requests per ip in date_range, unordered
+pub async fn count_ips_in_daterange(
+    coll: &Collection<LogEntry>,
     date_range: &DateRange,
-    logentry_coll: &Collection<LogEntry>,
-) -> anyhow::Result<()> {
-    let time_filter = doc! {"$match": {"time": {"$gte": date_range.start, "$lt": date_range.end}}};
-    let out_coll = doc! {"$out": "current_logentries"};
-    let _ = logentry_coll
-        .aggregate(vec![time_filter, out_coll], None)
+) -> anyhow::Result<HashMap<String, i64>> {
+    let mut cursor = coll
+        .aggregate(ips_in_daterange_pipeline(date_range), None)
         .await?;
-    Ok(())
+    let mut counts = HashMap::new();
+    while let Some(doc) = cursor.try_next().await? {
+        // * $sum gives an int64 only once a count outgrows int32
+        let count = match doc.get("count") {
+            Some(Bson::Int64(n)) => *n,
+            Some(Bson::Int32(n)) => i64::from(*n),
+            _ => 0,
+        };
+        counts.insert(doc.get_str("_id")?.to_string(), count);
+    }
+    Ok(counts)
 }
 
-// * must call make_current_le_coll before calling this!
-pub async fn get_current_ips_by_country(
-    current_logentries_coll: &Collection<LogEntry>,
-) -> anyhow::Result<Vec<CountryWithIps>> {
-    let pipeline = [
-        doc! {
-            "$lookup": doc! {
-                "as": "hostdata",
-                "from": "hostdata",
-                "foreignField": "ip",
-                "localField": "ip"
-            }
-        },
-        doc! {
-            "$project": doc! {
-                "ip": 1,
-                "hostdata.geodata.country_name": 1
-            }
-        },
-        doc! {
-            "$sort": doc! {
-                "hostdata.geodata.country_name": 1
-            }
-        },
-        doc! {
-            "$set": doc! {
-                "country": "$hostdata.geodata.country_name"
-            }
-        },
-        doc! {
-            "$project": doc! {
-                "hostdata": 0
-            }
-        },
-        doc! {
-            "$unwind": doc! {
-                "path": "$country",
-                "preserveNullAndEmptyArrays": false
-            }
-        },
-        doc! {
-            "$group": doc! {
-                "_id": "$country",
-                "ips": doc! {
-                    "$addToSet": "$ip"
-                }
-            }
-        },
-        doc! {"$sort": doc! {"_id": 1}},
-    ];
-    let curs = current_logentries_coll.aggregate(pipeline, None).await?;
-    let docs = curs.try_collect::<Vec<Document>>().await?;
-    let mut country_with_ip_list: Vec<CountryWithIps> = vec![];
-    for doc in docs {
-        let vip: CountryWithIps = bson::from_document(doc)?;
-        country_with_ip_list.push(vip);
+// * per-host digest for search --summary, between full entries and none
+#[derive(Debug, Serialize)]
+pub struct HostSummary {
+    pub requests: i64,
+    // * RFC 3339, UTC
+    pub first_seen: String,
+    pub last_seen: String,
+    // * status code -> count
+    pub status: BTreeMap<String, i64>,
+    pub top_paths: Vec<KeyCount>,
+    // * most frequent first
+    pub user_agents: Vec<KeyCount>,
+}
+
+impl fmt::Display for HostSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  requests: {}", self.requests)?;
+        writeln!(f, "  first seen: {}", self.first_seen)?;
+        writeln!(f, "  last seen: {}", self.last_seen)?;
+        let status: Vec<String> = self
+            .status
+            .iter()
+            .map(|(code, count)| format!("{code}: {count}"))
+            .collect();
+        writeln!(f, "  status: {}", status.join(", "))?;
+        writeln!(f, "  top paths:")?;
+        for kc in &self.top_paths {
+            writeln!(f, "    {:>8}  {}", kc.count, kc.key)?;
+        }
+        writeln!(f, "  user agents:")?;
+        for kc in &self.user_agents {
+            writeln!(f, "    {:>8}  {}", kc.count, kc.key)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryTotals {
+    #[serde(rename = "_id")]
+    ip: String,
+    requests: i64,
+    first_seen: DateTime,
+    last_seen: DateTime,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryStatus {
+    ip: String,
+    key: String,
+    count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryTop {
+    #[serde(rename = "_id")]
+    ip: String,
+    top: Vec<KeyCount>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryFacets {
+    totals: Vec<SummaryTotals>,
+    status: Vec<SummaryStatus>,
+    paths: Vec<SummaryTop>,
+    user_agents: Vec<SummaryTop>,
+}
+
+// * number of paths and user agents in a host summary
+const SUMMARY_PATHS: usize = 5;
+const SUMMARY_USER_AGENTS: usize = 5;
+
+// * hosts summarized per aggregation, keeping the $in list and the result document small
+const SUMMARY_CHUNK: usize = 500;
+
+// * every host's summary in one pass, grouped by ip
+fn summary_pipeline(ips: &[String]) -> Vec<Document> {
+    vec![
+        doc! {"$match": doc! {"ip": doc! {"$in": ips}}},
+        doc! {"$facet": doc! {
+            "totals": [doc! {"$group": doc! {
+                "_id": "$ip",
+                "requests": doc! {"$sum": 1},
+                "first_seen": doc! {"$min": "$time"},
+                "last_seen": doc! {"$max": "$time"},
+            }}],
+            "status": [
+                doc! {"$group": doc! {
+                    "_id": doc! {"ip": "$ip", "code": "$code"},
+                    "count": doc! {"$sum": 1},
+                }},
+                doc! {"$project": doc! {
+                    "_id": 0,
+                    "ip": "$_id.ip",
+                    "key": doc! {"$toString": "$_id.code"},
+                    "count": 1,
+                }},
+            ],
+            "paths": stats::top_n_per_ip(stats::path(), SUMMARY_PATHS),
+            "user_agents": stats::top_n_per_ip("$ua", SUMMARY_USER_AGENTS),
+        }},
+    ]
+}
+
+// * one summary pipeline per SUMMARY_CHUNK ips
+fn summary_pipelines(ips: &[String]) -> impl Iterator<Item = Vec<Document>> + '_ {
+    ips.chunks(SUMMARY_CHUNK).map(summary_pipeline)
+}
+
+// * the facets of one chunk as summaries; status and top lists only for ips with totals
+fn add_summaries(summaries: &mut HashMap<String, HostSummary>, facets: SummaryFacets) {
+    for totals in facets.totals {
+        summaries.insert(
+            totals.ip,
+            HostSummary {
+                requests: totals.requests,
+                first_seen: totals.first_seen.to_chrono().to_rfc3339(),
+                last_seen: totals.last_seen.to_chrono().to_rfc3339(),
+                status: BTreeMap::new(),
+                top_paths: vec![],
+                user_agents: vec![],
+            },
+        );
+    }
+    for status in facets.status {
+        if let Some(summary) = summaries.get_mut(&status.ip) {
+            summary.status.insert(status.key, status.count);
+        }
+    }
+    for paths in facets.paths {
+        if let Some(summary) = summaries.get_mut(&paths.ip) {
+            summary.top_paths = paths.top;
+        }
+    }
+    for user_agents in facets.user_agents {
+        if let Some(summary) = summaries.get_mut(&user_agents.ip) {
+            summary.user_agents = user_agents.top;
+        }
     }
-    Ok(country_with_ip_list)
 }
 
 // * must call make_current_le_coll before calling this!
-pub async fn get_current_ips_by_org(
+// * summaries by ip; ips without entries in the current range are left out
+pub async fn host_summaries(
     current_logentries_coll: &Collection<LogEntry>,
-) -> anyhow::Result<Vec<OrgWithIps>> {
-    let pipeline = [
-        doc! {
-            "$lookup": doc! {
-                "as": "hostdata",
-                "from": "hostdata",
-                "foreignField": "ip",
-                "localField": "ip"
-            }
-        },
-        doc! {
-            "$project": doc! {
-                "ip": 1,
-                "hostdata.geodata.organization": 1
-            }
-        },
-        doc! {
-            "$set": doc! {
-                "organization": "$hostdata.geodata.organization"
-            }
-        },
-        doc! {
-            "$project": doc! {
-                "hostdata": 0
-            }
-        },
-        doc! {
-            "$unwind": doc! {
-                "path": "$organization",
-                "preserveNullAndEmptyArrays": false
-            }
-        },
-        doc! {
-            "$group": doc! {
-                "_id": "$organization",
-                "ips": doc! {
-                    "$addToSet": "$ip"
-                }
-            }
-        },
-    ];
-    let curs = current_logentries_coll.aggregate(pipeline, None).await?;
-    let docs = curs.try_collect::<Vec<Document>>().await?;
-    let mut org_with_ip_list: Vec<OrgWithIps> = vec![];
-    for doc in docs {
-        let vip: OrgWithIps = bson::from_document(doc)?;
-        org_with_ip_list.push(vip);
+    ips: &[String],
+) -> anyhow::Result<HashMap<String, HostSummary>> {
+    let mut summaries = HashMap::new();
+    for pipeline in summary_pipelines(ips) {
+        let mut docs: Vec<Document> = current_logentries_coll
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        let facets: SummaryFacets = bson::from_document(
+            docs.pop()
+                .ok_or(anyhow!("summary aggregation returned nothing"))?,
+        )?;
+        add_summaries(&mut summaries, facets);
+    }
+    Ok(summaries)
+}
+
+// * stages selecting log entries in date_range passing entry_filters
+pub(crate) fn entry_stages(date_range: &DateRange, entry_filters: &EntryFilters) -> Vec<Document> {
+    let mut conditions = vec![doc! {"time": {"$gte": date_range.start, "$lt": date_range.end}}];
+    conditions.extend(entry_filters.conditions());
+    let mut stages = vec![doc! {"$match": {"$and": conditions}}];
+    stages.extend(entry_filters.path_stages());
+    stages
+}
+
+pub async fn make_current_le_coll(
+    date_range: &DateRange,
+    entry_filters: &EntryFilters,
+    logentry_coll: &Collection<LogEntry>,
+) -> anyhow::Result<()> {
+    let mut pipeline = entry_stages(date_range, entry_filters);
+    pipeline.push(doc! {"$out": "current_logentries"});
+    let _ = logentry_coll.aggregate(pipeline, None).await?;
+    // * $out leaves only the _id index, and hosts are looked up by ip
+    let current_coll: Collection<LogEntry> = logentry_coll
+        .client()
+        .database(&logentry_coll.namespace().db)
+        .collection("current_logentries");
+    let index = IndexModel::builder()
+        .keys(doc! {"ip": 1, "time": 1})
+        .build();
+    current_coll.create_index(index, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_combine_with_and() {
+        let filters = SearchFilters {
+            country: Some(vec!["China".to_string()]),
+            org: Some("Tencent".to_string()),
+            not_ip: Some("^10\\.".to_string()),
+            ..Default::default()
+        };
+        let pipeline = filters.pipeline();
+        let matchers: Vec<&Document> = pipeline
+            .iter()
+            .filter_map(|stage| stage.get_document("$match").ok())
+            .collect();
+        // * the ip filter goes before grouping, the host filters after the lookup
+        assert_eq!(matchers.len(), 2);
+        assert_eq!(matchers[0].get_array("$and").unwrap().len(), 1);
+        assert_eq!(matchers[1].get_array("$and").unwrap().len(), 2);
+        assert!(pipeline[1].contains_key("$group"));
+    }
+
+    #[test]
+    fn country_codes_are_rejected() {
+        assert!(country_name("US").is_err());
+        assert!(country_name("cn").is_err());
+        assert_eq!(country_name("China").unwrap(), "China");
+    }
+
+    #[test]
+    fn status_specs() {
+        let status: StatusFilter = "4xx, 502".parse().unwrap();
+        assert_eq!(status, StatusFilter(vec![(400, 499), (502, 502)]));
+        assert!("6xx".parse::<StatusFilter>().is_err());
+        assert!("abc".parse::<StatusFilter>().is_err());
+    }
+
+    #[test]
+    fn entry_filters_anchor_method() {
+        let filters = EntryFilters {
+            method: vec!["post".to_string(), "GET".to_string()],
+            ua_regex: Some("curl|python".to_string()),
+            path_regex: Some(r"\.php$".to_string()),
+            ..Default::default()
+        };
+        let conditions = filters.conditions();
+        assert_eq!(conditions.len(), 2);
+        match conditions[0].get("method") {
+            Some(Bson::RegularExpression(re)) => assert_eq!(re.pattern, "^(?:POST|GET) "),
+            other => panic!("expected a regex, got {other:?}"),
+        }
+        let path = filters.path_stages()[1]
+            .get_document("$match")
+            .unwrap()
+            .clone();
+        assert!(matches!(path.get("path"), Some(Bson::RegularExpression(_))));
+    }
+
+    #[test]
+    fn cidr_filters_use_ip_key() {
+        let filters = SearchFilters {
+            cidr: vec!["2a06:4880::/32".parse().unwrap()],
+            not_cidr: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let conditions = filters.conditions();
+        assert_eq!(conditions.len(), 2);
+        assert!(conditions[0].contains_key("$or"));
+        assert!(conditions[1].contains_key("$nor"));
+        let pipeline = filters.pipeline();
+        let matcher = pipeline[0].get_document("$match").unwrap();
+        assert_eq!(matcher.get_array("$and").unwrap().len(), 2);
+        let group = pipeline[1].get_document("$group").unwrap();
+        assert!(group.contains_key("ip_key"));
+    }
+
+    #[test]
+    fn host_regex_matches_ptr_records() {
+        let filters = SearchFilters {
+            host_regex: Some(r"\.amazonaws\.com$".to_string()),
+            ..Default::default()
+        };
+        let pattern = match filters.conditions()[0].get("ptr_records") {
+            Some(Bson::RegularExpression(re)) => re.pattern.clone(),
+            other => panic!("expected a regex, got {other:?}"),
+        };
+        let re = regex::Regex::new(&pattern).unwrap();
+        // * names are stored as resolved, with the root dot, which is trimmed before matching
+        let stored = "ec2-1-2-3-4.compute-1.amazonaws.com.";
+        assert!(!re.is_match(stored));
+        assert!(re.is_match(stored.trim_end_matches(PTR_ROOT)));
+        assert!(!re.is_match("amazonaws.com.evil.example"));
+    }
+
+    #[test]
+    fn page_token_round_trip() {
+        let token = encode_page_token(SortKey::Requests, &Bson::Int64(42), "192.0.2.1").unwrap();
+        let (value, ip) = decode_page_token(&token, SortKey::Requests).unwrap();
+        assert_eq!(value, Bson::Int64(42));
+        assert_eq!(ip, "192.0.2.1");
+        assert!(decode_page_token(&token, SortKey::Bytes).is_err());
+        assert!(decode_page_token("garbage", SortKey::Requests).is_err());
+    }
+
+    #[test]
+    fn next_page_follows_the_sort_direction() {
+        // * most requests first: the next page has fewer, or as many with a later ip
+        assert_eq!(
+            SortKey::Requests.after(Bson::Int64(42), "192.0.2.1"),
+            doc! {"$or": [
+                {"sort_key": {"$lt": 42_i64}},
+                {"sort_key": 42_i64, "ip": {"$gt": "192.0.2.1"}},
+            ]}
+        );
+        let after_first_seen = SortKey::FirstSeen.after(Bson::Int64(42), "192.0.2.1");
+        let beyond = after_first_seen.get_array("$or").unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert!(beyond.get_document("sort_key").unwrap().contains_key("$gt"));
+        // * one more host than the limit is fetched, to know whether there is a next page
+        let page = PageArgs {
+            limit: Some(10),
+            ..Default::default()
+        };
+        let limits: Vec<i64> = page
+            .stages()
+            .unwrap()
+            .iter()
+            .filter_map(|stage| stage.get_i64("$limit").ok())
+            .collect();
+        assert_eq!(limits, [11]);
+        assert_eq!(page.sort_key(), Some(SortKey::Ip));
+    }
+
+    #[test]
+    fn ip_sort_is_by_address() {
+        assert_eq!(
+            SortKey::Ip.expression(),
+            Bson::from(doc! {"$ifNull": ["$ip_key", cidr::no_key()]})
+        );
+        // * mongo orders binaries by length, then subtype, then bytes
+        let order = |key: bson::Binary| (key.bytes.len(), u8::from(key.subtype), key.bytes);
+        let mut ips = vec![
+            "2001:db8::1",
+            "10.0.0.10",
+            "legacy",
+            "10.0.0.9",
+            "9.255.0.1",
+        ];
+        ips.sort_by_key(|ip| order(cidr::ip_key(ip).unwrap_or_else(cidr::no_key)));
+        assert_eq!(
+            ips,
+            [
+                "legacy",
+                "9.255.0.1",
+                "10.0.0.9",
+                "10.0.0.10",
+                "2001:db8::1"
+            ]
+        );
+    }
+
+    #[test]
+    fn summaries_are_chunked() {
+        let ips: Vec<String> = (0..1201)
+            .map(|i| format!("10.0.{}.{}", i / 256, i % 256))
+            .collect();
+        let chunks: Vec<Vec<Bson>> = summary_pipelines(&ips)
+            .map(|pipeline| {
+                let matcher = pipeline
+                    .iter()
+                    .find_map(|stage| stage.get_document("$match").ok())
+                    .unwrap();
+                matcher
+                    .get_document("ip")
+                    .unwrap()
+                    .get_array("$in")
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+        let sizes: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [500, 500, 201]);
+        let all: Vec<Bson> = chunks.concat();
+        assert_eq!(
+            all,
+            ips.iter()
+                .map(|ip| Bson::from(ip.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn summaries_join_facets_by_ip() {
+        let t = DateTime::from_millis(1_733_738_400_000);
+        let facets: SummaryFacets = bson::from_document(doc! {
+            "totals": [{"_id": "192.0.2.1", "requests": 3_i64, "first_seen": t, "last_seen": t}],
+            "status": [
+                {"ip": "192.0.2.1", "key": "404", "count": 2_i64},
+                {"ip": "192.0.2.1", "key": "200", "count": 1_i64},
+                {"ip": "192.0.2.9", "key": "200", "count": 5_i64},
+            ],
+            "paths": [{"_id": "192.0.2.1", "top": [{"key": "/a.php", "count": 2_i64}]}],
+            "user_agents": [{"_id": "192.0.2.9", "top": [{"key": "curl", "count": 5_i64}]}],
+        })
+        .unwrap();
+        let mut summaries = HashMap::new();
+        add_summaries(&mut summaries, facets);
+        // * facets for an ip without totals are dropped
+        assert_eq!(summaries.len(), 1);
+        let summary = &summaries["192.0.2.1"];
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.status["404"], 2);
+        assert_eq!(summary.top_paths[0].key, "/a.php");
+        assert!(summary.user_agents.is_empty());
+    }
+
+    #[test]
+    fn no_filters_no_match_stage() {
+        let filters = SearchFilters::default();
+        assert!(filters.is_empty());
+        assert!(filters
+            .pipeline()
+            .iter()
+            .all(|stage| !stage.contains_key("$match")));
     }
-    Ok(org_with_ip_list)
 }
@@ -0,0 +1,544 @@
+// * pre-aggregated hourly and daily rollups of logentries
+// * rollups outlive pruned raw entries and keep summary queries fast over long ranges
+use anyhow::bail;
+use chrono::{DurationRound, TimeDelta};
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{FindOptions, ReplaceOptions, UpdateOptions};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::warn;
+
+use crate::log_entries::LogEntry;
+use crate::query::DateRange;
+use crate::{Config, HostData, Logdate};
+
+// * number of paths kept per bucket
+const TOP_PATHS: usize = 20;
+
+// * ips kept per bucket; busier buckets keep a sample, so unique ip counts are lower bounds
+const MAX_IPS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn coll_name(&self) -> &'static str {
+        match self {
+            Granularity::Hour => "rollups_hourly",
+            Granularity::Day => "rollups_daily",
+        }
+    }
+
+    fn span(&self) -> TimeDelta {
+        match self {
+            Granularity::Hour => TimeDelta::hours(1),
+            Granularity::Day => TimeDelta::days(1),
+        }
+    }
+
+    fn bucket_start(&self, time: Logdate) -> Logdate {
+        time.duration_trunc(self.span())
+            .expect("bucket spans should truncate any log time")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyCount {
+    pub key: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rollup {
+    #[serde(rename = "_id")]
+    pub start: bson::DateTime,
+    pub requests: i64,
+    pub bytes: i64,
+    pub status: BTreeMap<String, i64>,
+    // * at most MAX_IPS
+    pub ips: Vec<String>,
+    pub top_paths: Vec<KeyCount>,
+    pub countries: Vec<KeyCount>,
+}
+
+// * status class of an http code, e.g. 404 -> 4xx
+pub fn status_class(code: u32) -> String {
+    format!("{}xx", code / 100)
+}
+
+fn to_key_counts(counts: HashMap<String, i64>, limit: usize) -> Vec<KeyCount> {
+    let mut key_counts: Vec<KeyCount> = counts
+        .into_iter()
+        .map(|(key, count)| KeyCount { key, count })
+        .collect();
+    // * descending by count, ties broken by key so output is stable
+    key_counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    key_counts.truncate(limit);
+    key_counts
+}
+
+#[derive(Default)]
+struct Accumulator {
+    requests: i64,
+    bytes: i64,
+    status: BTreeMap<String, i64>,
+    ips: HashSet<String>,
+    paths: HashMap<String, i64>,
+    countries: HashMap<String, i64>,
+}
+
+// * countries maps ip -> country name; ips without hostdata count as "unknown"
+pub fn build_rollups(
+    logentries: &[LogEntry],
+    countries: &HashMap<String, String>,
+    granularity: Granularity,
+) -> Vec<Rollup> {
+    bucket_rollups(logentries, countries, granularity, TOP_PATHS)
+}
+
+fn bucket_rollups(
+    logentries: &[LogEntry],
+    countries: &HashMap<String, String>,
+    granularity: Granularity,
+    path_limit: usize,
+) -> Vec<Rollup> {
+    let mut buckets: BTreeMap<Logdate, Accumulator> = BTreeMap::new();
+    for le in logentries {
+        let start = granularity.bucket_start(le.time.to_chrono());
+        let acc = buckets.entry(start).or_default();
+        acc.requests += 1;
+        acc.bytes += i64::from(le.nbytes);
+        *acc.status.entry(status_class(le.code)).or_insert(0) += 1;
+        acc.ips.insert(le.ip.clone());
+        *acc.paths.entry(le.path().to_string()).or_insert(0) += 1;
+        let country = countries
+            .get(&le.ip)
+            .map(String::as_str)
+            .unwrap_or("unknown");
+        *acc.countries.entry(country.to_string()).or_insert(0) += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(start, acc)| {
+            let mut ips: Vec<String> = acc.ips.into_iter().collect();
+            ips.sort();
+            ips.truncate(MAX_IPS);
+            Rollup {
+                start: start.into(),
+                requests: acc.requests,
+                bytes: acc.bytes,
+                status: acc.status,
+                ips,
+                top_paths: to_key_counts(acc.paths, path_limit),
+                countries: to_key_counts(acc.countries, usize::MAX),
+            }
+        })
+        .collect()
+}
+
+// * widen a range so that it covers whole buckets
+fn bucket_aligned(date_range: &DateRange, granularity: Granularity) -> DateRange {
+    let start = granularity.bucket_start(date_range.start.to_chrono());
+    let mut end = granularity.bucket_start(date_range.end.to_chrono());
+    if end < date_range.end.to_chrono() {
+        end += granularity.span();
+    }
+    DateRange {
+        start: start.into(),
+        end: end.into(),
+    }
+}
+
+async fn countries_for_ips(
+    db: &Database,
+    ips: Vec<String>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let hostdata_coll: Collection<HostData> = db.collection("hostdata");
+    let mut curs = hostdata_coll
+        .find(doc! {"ip": doc! {"$in": ips}}, None)
+        .await?;
+    let mut countries = HashMap::new();
+    while let Some(hd) = curs.try_next().await? {
+        countries.insert(hd.ip, hd.geodata.country_name);
+    }
+    Ok(countries)
+}
+
+// * expression adding delta to a stored list of key counts, keeping the largest limit
+// * ($sortArray needs MongoDB 5.2); values from log lines are $literal, since a path
+// * like $foo would otherwise be read as a field path
+fn merge_counts(field: &str, delta: &[KeyCount], limit: Option<usize>) -> anyhow::Result<Bson> {
+    let merged = doc! {"$sortArray": doc! {
+        "input": doc! {"$map": doc! {
+            "input": doc! {"$setUnion": ["$$all.key"]},
+            "as": "k",
+            "in": doc! {
+                "key": "$$k",
+                "count": doc! {"$sum": doc! {"$map": doc! {
+                    "input": doc! {"$filter": doc! {
+                        "input": "$$all",
+                        "cond": doc! {"$eq": ["$$this.key", "$$k"]},
+                    }},
+                    "in": "$$this.count",
+                }}},
+            },
+        }},
+        "sortBy": doc! {"count": -1, "key": 1},
+    }};
+    let merged = match limit {
+        Some(limit) => doc! {"$slice": [merged, limit as i64]},
+        None => merged,
+    };
+    Ok(doc! {"$let": doc! {
+        "vars": doc! {"all": doc! {"$concatArrays": [
+            doc! {"$ifNull": [format!("${field}"), []]},
+            doc! {"$literal": bson::to_bson(delta)?},
+        ]}},
+        "in": merged,
+    }}
+    .into())
+}
+
+// * pipeline update adding one bucket's new entries to its stored rollup, atomically
+fn add_update(delta: &Rollup) -> anyhow::Result<Vec<Document>> {
+    let mut set = doc! {
+        "requests": doc! {"$add": [
+            doc! {"$ifNull": ["$requests", 0_i64]},
+            doc! {"$literal": delta.requests},
+        ]},
+        "bytes": doc! {"$add": [
+            doc! {"$ifNull": ["$bytes", 0_i64]},
+            doc! {"$literal": delta.bytes},
+        ]},
+        "ips": doc! {"$slice": [
+            doc! {"$setUnion": [doc! {"$ifNull": ["$ips", []]}, doc! {"$literal": &delta.ips}]},
+            MAX_IPS as i64,
+        ]},
+        "top_paths": merge_counts("top_paths", &delta.top_paths, Some(TOP_PATHS))?,
+        "countries": merge_counts("countries", &delta.countries, None)?,
+    };
+    for (class, count) in &delta.status {
+        let field = format!("status.{class}");
+        set.insert(
+            field.clone(),
+            doc! {"$add": [
+                doc! {"$ifNull": [format!("${field}"), 0_i64]},
+                doc! {"$literal": count},
+            ]},
+        );
+    }
+    Ok(vec![doc! {"$set": set}])
+}
+
+// * add newly inserted entries to the rollups of their buckets; entries must be new, or
+// * they are counted twice
+pub async fn add_to_rollups(db: &Database, logentries: &[LogEntry]) -> anyhow::Result<()> {
+    if logentries.is_empty() {
+        return Ok(());
+    }
+    let ips: HashSet<String> = logentries.iter().map(|le| le.ip.clone()).collect();
+    let countries = countries_for_ips(db, ips.into_iter().collect()).await?;
+    for granularity in [Granularity::Hour, Granularity::Day] {
+        let rollup_coll: Collection<Rollup> = db.collection(granularity.coll_name());
+        // * paths are merged in full, then cut to TOP_PATHS against the stored counts
+        for delta in bucket_rollups(logentries, &countries, granularity, usize::MAX) {
+            let options = UpdateOptions::builder().upsert(true).build();
+            rollup_coll
+                .update_one(doc! {"_id": delta.start}, add_update(&delta)?, options)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// * recompute rollups from raw entries for every bucket overlapping date_range
+// * a bucket whose raw entries were partly pruned would lose counts, so a stored rollup
+// * with more requests than the raw entries left is kept; returns the number kept
+pub async fn update_rollups(db: &Database, date_range: &DateRange) -> anyhow::Result<usize> {
+    let logents_coll: Collection<LogEntry> = db.collection("logentries");
+    let mut kept = 0;
+    for granularity in [Granularity::Hour, Granularity::Day] {
+        let aligned = bucket_aligned(date_range, granularity);
+        let filter = doc! {"time": {"$gte": aligned.start, "$lt": aligned.end}};
+        // * the raw line is not rolled up, so it is not fetched
+        let options = FindOptions::builder().projection(doc! {"line": 0}).build();
+        let logentries: Vec<LogEntry> = logents_coll
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+        let ips: HashSet<String> = logentries.iter().map(|le| le.ip.clone()).collect();
+        let countries = countries_for_ips(db, ips.into_iter().collect()).await?;
+        let rollup_coll: Collection<Rollup> = db.collection(granularity.coll_name());
+        let stored: HashMap<bson::DateTime, i64> = rollup_coll
+            .find(
+                doc! {"_id": {"$gte": aligned.start, "$lt": aligned.end}},
+                None,
+            )
+            .await?
+            .map_ok(|rollup| (rollup.start, rollup.requests))
+            .try_collect()
+            .await?;
+        for rollup in build_rollups(&logentries, &countries, granularity) {
+            if stored
+                .get(&rollup.start)
+                .is_some_and(|&requests| requests > rollup.requests)
+            {
+                kept += 1;
+                continue;
+            }
+            let options = ReplaceOptions::builder().upsert(true).build();
+            rollup_coll
+                .replace_one(doc! {"_id": rollup.start}, &rollup, options)
+                .await?;
+        }
+    }
+    Ok(kept)
+}
+
+pub async fn rebuild(start: &str, end: &str, config: &Config) -> anyhow::Result<()> {
+    let date_range = crate::query::time_str_to_daterange(start, end)?;
+    let (db, _, _) = crate::setup_db(config).await?;
+    let kept = update_rollups(&db, &date_range).await?;
+    println!(
+        "Rebuilt rollups from {} to {}",
+        date_range.start, date_range.end
+    );
+    if kept > 0 {
+        warn!("Kept {kept} rollups whose raw entries were partly pruned");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct RollupSummary {
+    pub requests: i64,
+    pub bytes: i64,
+    pub status: BTreeMap<String, i64>,
+    pub unique_ips: usize,
+    // * set when a bucket kept only a sample of its ips, making unique_ips a lower bound
+    pub ips_capped: bool,
+    // * merged from per-bucket top paths, so counts for rare paths are lower bounds
+    pub top_paths: Vec<KeyCount>,
+    pub countries: Vec<KeyCount>,
+}
+
+pub fn summarize(rollups: &[Rollup], top_n: usize) -> RollupSummary {
+    let mut summary = RollupSummary::default();
+    let mut ips = HashSet::new();
+    let mut paths = HashMap::new();
+    let mut countries = HashMap::new();
+    for rollup in rollups {
+        summary.requests += rollup.requests;
+        summary.bytes += rollup.bytes;
+        for (class, count) in &rollup.status {
+            *summary.status.entry(class.clone()).or_insert(0) += count;
+        }
+        ips.extend(rollup.ips.iter());
+        summary.ips_capped |= rollup.ips.len() >= MAX_IPS;
+        for kc in &rollup.top_paths {
+            *paths.entry(kc.key.clone()).or_insert(0) += kc.count;
+        }
+        for kc in &rollup.countries {
+            *countries.entry(kc.key.clone()).or_insert(0) += kc.count;
+        }
+    }
+    summary.unique_ips = ips.len();
+    summary.top_paths = to_key_counts(paths, top_n);
+    summary.countries = to_key_counts(countries, top_n);
+    summary
+}
+
+pub(crate) fn print_key_counts(heading: &str, key_counts: &[KeyCount]) {
+    println!("{}", style(heading).red());
+    for kc in key_counts {
+        println!("  {:>8}  {}", kc.count, kc.key);
+    }
+}
+
+pub async fn summary(start: &str, end: &str, hourly: &bool, config: &Config) -> anyhow::Result<()> {
+    let date_range = crate::query::time_str_to_daterange(start, end)?;
+    let granularity = if *hourly {
+        Granularity::Hour
+    } else {
+        Granularity::Day
+    };
+    let (db, _, _) = crate::setup_db(config).await?;
+    let rollup_coll: Collection<Rollup> = db.collection(granularity.coll_name());
+    let filter = doc! {"_id": {"$gte": date_range.start, "$lt": date_range.end}};
+    let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+    let rollups: Vec<Rollup> = rollup_coll
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?;
+    if rollups.is_empty() {
+        bail!("No rollups in range; run `loglook rollup` to build them");
+    }
+    for rollup in &rollups {
+        println!(
+            "{}  requests: {:>7}  bytes: {:>11}  ips: {:>5}",
+            style(rollup.start).yellow(),
+            rollup.requests,
+            rollup.bytes,
+            rollup.ips.len()
+        );
+    }
+    let summary = summarize(&rollups, 10);
+    println!("----------");
+    println!("{}: {}", style("Requests").red(), summary.requests);
+    println!("{}: {}", style("Bytes").red(), summary.bytes);
+    if summary.ips_capped {
+        println!(
+            "{}: at least {}",
+            style("Unique IPs").red(),
+            summary.unique_ips
+        );
+    } else {
+        println!("{}: {}", style("Unique IPs").red(), summary.unique_ips);
+    }
+    for (class, count) in &summary.status {
+        println!("{}: {}", style(class).red(), count);
+    }
+    print_key_counts("Top paths", &summary.top_paths);
+    print_key_counts("Top countries", &summary.countries);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logentry(ip: &str, time: &str, code: u32, method: &str) -> LogEntry {
+        let time: Logdate = time.parse().unwrap();
+        LogEntry {
+            ip: ip.to_string(),
+            ip_key: None,
+            time: time.into(),
+            method: method.to_string(),
+            code,
+            nbytes: 100,
+            referrer: "-".to_string(),
+            ua: "curl".to_string(),
+            line: String::new(),
+        }
+    }
+
+    fn sample() -> Vec<LogEntry> {
+        vec![
+            logentry("1.2.3.4", "2024-12-09T10:05:00Z", 200, "GET / HTTP/1.1"),
+            logentry(
+                "1.2.3.4",
+                "2024-12-09T10:45:00Z",
+                404,
+                "GET /a.php HTTP/1.1",
+            ),
+            logentry(
+                "5.6.7.8",
+                "2024-12-09T11:00:00Z",
+                404,
+                "GET /a.php HTTP/1.1",
+            ),
+        ]
+    }
+
+    #[test]
+    fn hourly_rollups_test() {
+        let countries = HashMap::from([("1.2.3.4".to_string(), "Germany".to_string())]);
+        let rollups = build_rollups(&sample(), &countries, Granularity::Hour);
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].requests, 2);
+        assert_eq!(rollups[0].bytes, 200);
+        assert_eq!(rollups[0].status.get("4xx"), Some(&1));
+        assert_eq!(rollups[0].ips, vec!["1.2.3.4".to_string()]);
+        assert_eq!(rollups[1].countries[0].key, "unknown");
+    }
+
+    #[test]
+    fn daily_summary_test() {
+        let rollups = build_rollups(&sample(), &HashMap::new(), Granularity::Day);
+        assert_eq!(rollups.len(), 1);
+        let summary = summarize(&rollups, 1);
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.unique_ips, 2);
+        assert_eq!(
+            summary.top_paths,
+            vec![KeyCount {
+                key: "/a.php".to_string(),
+                count: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn add_update_increments() {
+        let rollups = bucket_rollups(&sample(), &HashMap::new(), Granularity::Day, usize::MAX);
+        let update = add_update(&rollups[0]).unwrap();
+        let set = update[0].get_document("$set").unwrap();
+        let requests = set
+            .get_document("requests")
+            .unwrap()
+            .get_array("$add")
+            .unwrap();
+        assert_eq!(requests[1], Bson::Document(doc! {"$literal": 3_i64}));
+        assert!(set.contains_key("status.4xx"));
+        assert!(set.contains_key("status.2xx"));
+        let ips = set
+            .get_document("ips")
+            .unwrap()
+            .get_array("$slice")
+            .unwrap();
+        assert_eq!(ips[1], Bson::Int64(MAX_IPS as i64));
+    }
+
+    #[test]
+    fn add_update_keeps_paths_literal() {
+        let entries = vec![logentry(
+            "1.2.3.4",
+            "2024-12-09T10:05:00Z",
+            404,
+            "GET $$x HTTP/1.1",
+        )];
+        let rollups = bucket_rollups(&entries, &HashMap::new(), Granularity::Day, usize::MAX);
+        let update = add_update(&rollups[0]).unwrap();
+        let merge = update[0]
+            .get_document("$set")
+            .and_then(|set| set.get_document("top_paths"))
+            .and_then(|paths| paths.get_document("$let"))
+            .and_then(|merge| merge.get_document("vars"))
+            .and_then(|vars| vars.get_document("all"))
+            .and_then(|all| all.get_array("$concatArrays"))
+            .unwrap();
+        let delta = merge[1]
+            .as_document()
+            .unwrap()
+            .get_array("$literal")
+            .unwrap();
+        assert_eq!(
+            delta[0].as_document().unwrap().get_str("key").unwrap(),
+            "$$x"
+        );
+    }
+
+    #[test]
+    fn bucket_aligned_test() {
+        let date_range =
+            crate::query::time_str_to_daterange("2024-12-09T10:05:00Z", "2024-12-09T11:00:00Z")
+                .unwrap();
+        let aligned = bucket_aligned(&date_range, Granularity::Hour);
+        assert_eq!(
+            aligned.start.try_to_rfc3339_string().unwrap(),
+            "2024-12-09T10:00:00Z"
+        );
+        assert_eq!(
+            aligned.end.try_to_rfc3339_string().unwrap(),
+            "2024-12-09T11:00:00Z"
+        );
+    }
+}
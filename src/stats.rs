@@ -0,0 +1,345 @@
+// * summary numbers and top-N breakdowns over raw log entries, in one $facet aggregation
+use anyhow::anyhow;
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::output::{self, OutputFormat};
+use crate::query::{self, EntryFilters};
+use crate::rollup::{print_key_counts, KeyCount};
+use crate::timespec::RangeArgs;
+use crate::Config;
+
+// * keep the n largest {_id, count} groups, or all of them, as {key, count}
+// * without a limit every key is kept and their order does not matter
+fn largest(n: Option<usize>) -> Vec<Document> {
+    let mut stages = vec![];
+    if let Some(n) = n {
+        stages.push(doc! {"$sort": doc! {"count": -1, "_id": 1}});
+        stages.push(doc! {"$limit": n as i64});
+    }
+    // * a missing key, e.g. no path in a malformed request, becomes ""
+    stages.push(doc! {"$project": doc! {
+        "_id": 0,
+        "key": doc! {"$ifNull": [doc! {"$toString": "$_id"}, ""]},
+        "count": 1,
+    }});
+    stages
+}
+
+fn count_by(key: impl Into<Bson>, n: Option<usize>) -> Vec<Document> {
+    let mut pipeline = vec![doc! {"$group": doc! {"_id": key.into(), "count": doc! {"$sum": 1}}}];
+    pipeline.extend(largest(n));
+    pipeline
+}
+
+// * group by key, a field path or expression, and keep the n largest
+pub(crate) fn top_n(key: impl Into<Bson>, n: usize) -> Vec<Document> {
+    count_by(key, Some(n))
+}
+
+// * like top_n, separately for each ip: one {_id: ip, top: [{key, count}]} per ip
+// * ($topN needs MongoDB 5.2)
+pub(crate) fn top_n_per_ip(key: impl Into<Bson>, n: usize) -> Vec<Document> {
+    vec![
+        doc! {"$group": doc! {
+            "_id": doc! {"ip": "$ip", "key": key.into()},
+            "count": doc! {"$sum": 1},
+        }},
+        doc! {"$group": doc! {
+            "_id": "$_id.ip",
+            "top": doc! {"$topN": doc! {
+                "n": n as i64,
+                "sortBy": doc! {"count": -1, "_id.key": 1},
+                "output": doc! {
+                    "key": doc! {"$ifNull": [doc! {"$toString": "$_id.key"}, ""]},
+                    "count": "$count",
+                },
+            }},
+        }},
+    ]
+}
+
+// * like top_n, keeping every group
+pub(crate) fn all_counts(key: impl Into<Bson>) -> Vec<Document> {
+    count_by(key, None)
+}
+
+// * like top_n, for a hostdata field: requests per ip are summed under the ip's field value
+fn top_n_by_host(field: &str, n: usize) -> Vec<Document> {
+    count_by_host(field, Some(n))
+}
+
+// * like top_n_by_host, keeping every group
+pub(crate) fn all_counts_by_host(field: &str) -> Vec<Document> {
+    count_by_host(field, None)
+}
+
+fn count_by_host(field: &str, n: Option<usize>) -> Vec<Document> {
+    let mut pipeline = vec![
+        doc! {"$group": doc! {"_id": "$ip", "count": doc! {"$sum": 1}}},
+        doc! {
+            "$lookup": doc! {
+                "as": "hostdata",
+                "from": "hostdata",
+                "foreignField": "ip",
+                "localField": "_id"
+            }
+        },
+        doc! {"$project": doc! {
+            "count": 1,
+            "key": doc! {"$ifNull": [doc! {"$first": format!("$hostdata.geodata.{field}")}, "unknown"]},
+        }},
+        doc! {"$group": doc! {"_id": "$key", "count": doc! {"$sum": "$count"}}},
+    ];
+    pipeline.extend(largest(n));
+    pipeline
+}
+
+// * status class of the entry's code, e.g. "4xx"
+pub(crate) fn status_class() -> Document {
+    doc! {"$concat": [
+        doc! {"$toString": doc! {"$toInt": doc! {"$floor": doc! {"$divide": ["$code", 100]}}}},
+        "xx",
+    ]}
+}
+
+// * request path, the middle part of the method field
+pub(crate) fn path() -> Document {
+    doc! {"$arrayElemAt": [doc! {"$split": ["$method", " "]}, 1]}
+}
+
+fn facets(n: usize) -> Document {
+    doc! {
+        "totals": [
+            doc! {"$group": doc! {
+                "_id": null,
+                "requests": doc! {"$sum": 1},
+                "bytes": doc! {"$sum": "$nbytes"},
+            }},
+        ],
+        "unique_ips": [doc! {"$group": doc! {"_id": "$ip"}}, doc! {"$count": "count"}],
+        "status": top_n(status_class(), 10),
+        "ips": top_n("$ip", n),
+        "countries": top_n_by_host("country_name", n),
+        "orgs": top_n_by_host("organization", n),
+        "paths": top_n(path(), n),
+        "user_agents": top_n("$ua", n),
+        "referrers": top_n("$referrer", n),
+    }
+}
+
+fn pipeline(entry_stages: Vec<Document>, n: usize) -> Vec<Document> {
+    let mut pipeline = entry_stages;
+    pipeline.push(doc! {"$facet": facets(n)});
+    pipeline
+}
+
+#[derive(Debug, Deserialize)]
+struct Totals {
+    requests: i64,
+    bytes: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Count {
+    count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Facets {
+    totals: Vec<Totals>,
+    unique_ips: Vec<Count>,
+    status: Vec<KeyCount>,
+    ips: Vec<KeyCount>,
+    countries: Vec<KeyCount>,
+    orgs: Vec<KeyCount>,
+    paths: Vec<KeyCount>,
+    user_agents: Vec<KeyCount>,
+    referrers: Vec<KeyCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub start: String,
+    pub end: String,
+    pub requests: i64,
+    pub unique_ips: i64,
+    pub bytes: i64,
+    pub status: BTreeMap<String, i64>,
+    pub top_ips: Vec<KeyCount>,
+    pub top_countries: Vec<KeyCount>,
+    pub top_orgs: Vec<KeyCount>,
+    pub top_paths: Vec<KeyCount>,
+    pub top_user_agents: Vec<KeyCount>,
+    pub top_referrers: Vec<KeyCount>,
+}
+
+impl Stats {
+    fn from_facets(start: String, end: String, facets: Facets) -> Stats {
+        let totals = facets.totals.first();
+        Stats {
+            start,
+            end,
+            requests: totals.map_or(0, |t| t.requests),
+            unique_ips: facets.unique_ips.first().map_or(0, |c| c.count),
+            bytes: totals.map_or(0, |t| t.bytes),
+            status: facets
+                .status
+                .into_iter()
+                .map(|kc| (kc.key, kc.count))
+                .collect(),
+            top_ips: facets.ips,
+            top_countries: facets.countries,
+            top_orgs: facets.orgs,
+            top_paths: facets.paths,
+            top_user_agents: facets.user_agents,
+            top_referrers: facets.referrers,
+        }
+    }
+
+    fn sections(&self) -> [(&'static str, &[KeyCount]); 6] {
+        [
+            ("ips", &self.top_ips),
+            ("countries", &self.top_countries),
+            ("orgs", &self.top_orgs),
+            ("paths", &self.top_paths),
+            ("user_agents", &self.top_user_agents),
+            ("referrers", &self.top_referrers),
+        ]
+    }
+
+    fn print(&self) {
+        println!(
+            "{} to {}",
+            style(&self.start).yellow(),
+            style(&self.end).yellow()
+        );
+        println!("{}: {}", style("Requests").red(), self.requests);
+        println!("{}: {}", style("Bytes").red(), self.bytes);
+        println!("{}: {}", style("Unique IPs").red(), self.unique_ips);
+        for (class, count) in &self.status {
+            println!("{}: {}", style(class).red(), count);
+        }
+        for (name, key_counts) in self.sections() {
+            print_key_counts(&format!("Top {}", name.replace('_', " ")), key_counts);
+        }
+    }
+
+    // * long form for csv: one section,key,count row per number
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut rows = vec![
+            vec![
+                "total".to_string(),
+                "requests".to_string(),
+                self.requests.to_string(),
+            ],
+            vec![
+                "total".to_string(),
+                "bytes".to_string(),
+                self.bytes.to_string(),
+            ],
+            vec![
+                "total".to_string(),
+                "unique_ips".to_string(),
+                self.unique_ips.to_string(),
+            ],
+        ];
+        for (class, count) in &self.status {
+            rows.push(vec!["status".to_string(), class.clone(), count.to_string()]);
+        }
+        for (name, key_counts) in self.sections() {
+            for kc in key_counts {
+                rows.push(vec![name.to_string(), kc.key.clone(), kc.count.to_string()]);
+            }
+        }
+        rows
+    }
+}
+
+pub async fn stats(
+    range: &RangeArgs,
+    filters: &EntryFilters,
+    top: usize,
+    format: OutputFormat,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let date_range = range.date_range()?;
+    let (_, _, logents_coll) = crate::setup_db(config).await?;
+    let stages = query::entry_stages(&date_range, filters);
+    let mut docs: Vec<Document> = logents_coll
+        .aggregate(pipeline(stages, top), None)
+        .await?
+        .try_collect()
+        .await?;
+    let facets: Facets = bson::from_document(
+        docs.pop()
+            .ok_or(anyhow!("stats aggregation returned nothing"))?,
+    )?;
+    let stats = Stats::from_facets(
+        date_range.start.to_chrono().to_rfc3339(),
+        date_range.end.to_chrono().to_rfc3339(),
+        facets,
+    );
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Table => stats.print(),
+        OutputFormat::Json => output::write_json(&mut out, &[stats])?,
+        OutputFormat::Ndjson => output::write_ndjson(&mut out, &[stats])?,
+        OutputFormat::Csv => {
+            output::write_csv(&mut out, &["section", "key", "count"], stats.rows())?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn facets_cover_every_breakdown() {
+        let facets = facets(5);
+        for name in [
+            "totals",
+            "unique_ips",
+            "status",
+            "ips",
+            "countries",
+            "orgs",
+            "paths",
+            "user_agents",
+            "referrers",
+        ] {
+            assert!(facets.contains_key(name), "missing facet {name}");
+        }
+        let limits: Vec<i64> = facets
+            .get_array("ips")
+            .unwrap()
+            .iter()
+            .filter_map(|stage| stage.as_document()?.get_i64("$limit").ok())
+            .collect();
+        assert_eq!(limits, [5]);
+    }
+
+    #[test]
+    fn only_a_limit_needs_a_sort() {
+        let has_sort = |stages: Vec<Document>| stages.iter().any(|s| s.contains_key("$sort"));
+        assert!(has_sort(top_n("$ip", 5)));
+        assert!(!has_sort(all_counts("$ip")));
+    }
+
+    #[test]
+    fn stats_from_empty_range() {
+        let facets: Facets = bson::from_document(doc! {
+            "totals": [], "unique_ips": [], "status": [], "ips": [], "countries": [],
+            "orgs": [], "paths": [], "user_agents": [], "referrers": [],
+        })
+        .unwrap();
+        let stats = Stats::from_facets("a".to_string(), "b".to_string(), facets);
+        assert_eq!(stats.requests, 0);
+        assert_eq!(stats.rows().len(), 3);
+    }
+}
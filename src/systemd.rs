@@ -0,0 +1,97 @@
+// * systemd Type=notify support; every notification is a no-op unless started by systemd
+use sd_notify::NotifyState;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::Counts;
+
+// * the sd_notify protocol: one datagram of NAME=value lines to the socket systemd listens on
+fn send(socket: &Path, states: &[NotifyState]) -> std::io::Result<()> {
+    let message: String = states.iter().map(|state| format!("{state}\n")).collect();
+    UnixDatagram::unbound()?.send_to(message.as_bytes(), socket)?;
+    Ok(())
+}
+
+fn notify(states: &[NotifyState]) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(Path::new(&socket), states) {
+        debug!("sd_notify failed: {e}");
+    }
+}
+
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn notify_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+// * WatchdogSec from the unit, if the watchdog is enabled for this process
+pub fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+}
+
+pub fn status_line(counts: &Counts) -> String {
+    let datetime = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S");
+    format!(
+        "Last cycle {datetime}: {} entries, {} inserted, {} skipped, {} new ips",
+        counts.n_logents, counts.n_inserted_les, counts.n_skipped_les, counts.n_new_ips
+    )
+}
+
+// * the example unit shipped alongside the source, with the @...@ placeholders filled in
+pub fn unit_file(exe: &str, user: &str, logfile: &str) -> String {
+    include_str!("loglook.service")
+        .replace("@USER@", user)
+        .replace("@EXE@", exe)
+        .replace("@LOGFILE@", logfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_to_notify_socket() {
+        let path = std::env::temp_dir().join(format!("loglook-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send(&path, &[NotifyState::Ready]).unwrap();
+        send(&path, &[NotifyState::Status("Last cycle: 3 entries")]).unwrap();
+        let mut buf = [0u8; 256];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\n");
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=Last cycle: 3 entries\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unit_is_notify_type() {
+        let unit = unit_file("/usr/bin/loglook", "art", "/var/log/nginx/access.log");
+        assert!(unit.contains("Type=notify"));
+        assert!(unit.contains("User=art\n"));
+        assert!(!unit.contains('@'));
+        assert!(unit.contains(
+            "ExecStart=/usr/bin/loglook --log-format journald read -d /var/log/nginx/access.log"
+        ));
+    }
+}
@@ -0,0 +1,472 @@
+// * requests per time bucket, optionally split into series, drawn as bars or sparklines
+use anyhow::bail;
+use chrono::{DateTime, NaiveTime, Offset, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use console::style;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::output::{self, OutputFormat};
+use crate::query::{self, EntryFilters};
+use crate::stats;
+use crate::timespec::RangeArgs;
+use crate::Config;
+
+// * more buckets than this is almost certainly a mistake, e.g. minutes over a year
+const MAX_BUCKETS: i64 = 100_000;
+const BAR_WIDTH: usize = 50;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BucketSize {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl BucketSize {
+    fn unit(&self) -> &'static str {
+        match self {
+            BucketSize::Minute => "minute",
+            BucketSize::Hour => "hour",
+            BucketSize::Day => "day",
+        }
+    }
+
+    // * start of the bucket holding time, the same way $dateTrunc cuts it in zone
+    fn start(&self, time: DateTime<Utc>, zone: Tz) -> DateTime<Utc> {
+        let span = match self {
+            BucketSize::Minute => TimeDelta::minutes(1),
+            BucketSize::Hour => TimeDelta::hours(1),
+            BucketSize::Day => {
+                let midnight = time
+                    .with_timezone(&zone)
+                    .date_naive()
+                    .and_time(NaiveTime::MIN);
+                // * where clocks skip midnight the day starts when they land
+                return zone
+                    .from_local_datetime(&midnight)
+                    .earliest()
+                    .map_or(time, |t| t.with_timezone(&Utc));
+            }
+        };
+        let offset = zone.offset_from_utc_datetime(&time.naive_utc()).fix();
+        let ms = span.num_milliseconds();
+        let local_ms = time.timestamp_millis() + i64::from(offset.local_minus_utc()) * 1000;
+        time - TimeDelta::milliseconds(local_ms.rem_euclid(ms))
+    }
+
+    // * start of the bucket after the one starting at start; days vary in length around DST
+    fn next(&self, start: DateTime<Utc>, zone: Tz) -> DateTime<Utc> {
+        match self {
+            BucketSize::Minute => start + TimeDelta::minutes(1),
+            BucketSize::Hour => self.start(start + TimeDelta::minutes(90), zone),
+            BucketSize::Day => self.start(start + TimeDelta::hours(36), zone),
+        }
+    }
+
+    fn label(&self, time: &DateTime<Tz>) -> String {
+        let fmt = match self {
+            BucketSize::Minute => "%Y-%m-%d %H:%M",
+            BucketSize::Hour => "%Y-%m-%d %H:00",
+            BucketSize::Day => "%Y-%m-%d",
+        };
+        time.format(fmt).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Split {
+    Status,
+    Country,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Chart {
+    Bars,
+    Sparkline,
+}
+
+// * how counts are divided into series
+#[derive(Debug, Clone, PartialEq)]
+pub enum Series {
+    All,
+    By(Split),
+    // * one ip against all the rest
+    Ip(String),
+}
+
+// * buckets start on the minute, hour or midnight in zone; needs MongoDB 5.0 for $dateTrunc
+pub(crate) fn bucket_start(size: BucketSize, zone: Tz) -> Document {
+    doc! {"$dateTrunc": doc! {"date": "$time", "unit": size.unit(), "timezone": zone.name()}}
+}
+
+// * entry_stages select the entries; the rest counts them per bucket and series
+fn pipeline(
+    entry_stages: Vec<Document>,
+    size: BucketSize,
+    zone: Tz,
+    series: &Series,
+) -> Vec<Document> {
+    let mut pipeline = entry_stages;
+    pipeline.extend(counts(size, zone, series));
+    pipeline
+}
+
+fn counts(size: BucketSize, zone: Tz, series: &Series) -> Vec<Document> {
+    let bucket = bucket_start(size, zone);
+    let key: Bson = match series {
+        Series::All => doc! {"$literal": "all"}.into(),
+        Series::By(Split::Status) => stats::status_class().into(),
+        Series::Ip(ip) => doc! {"$cond": [doc! {"$eq": ["$ip", ip]}, ip, "other"]}.into(),
+        Series::By(Split::Country) => {
+            // * count per ip first so each ip's hostdata is looked up once per bucket
+            return vec![
+                doc! {"$group": doc! {
+                    "_id": doc! {"bucket": bucket, "ip": "$ip"},
+                    "count": doc! {"$sum": 1},
+                }},
+                doc! {
+                    "$lookup": doc! {
+                        "as": "hostdata",
+                        "from": "hostdata",
+                        "foreignField": "ip",
+                        "localField": "_id.ip"
+                    }
+                },
+                doc! {"$group": doc! {
+                    "_id": doc! {
+                        "bucket": "$_id.bucket",
+                        "series": doc! {"$ifNull": [doc! {"$first": "$hostdata.geodata.country_name"}, "unknown"]},
+                    },
+                    "count": doc! {"$sum": "$count"},
+                }},
+                doc! {"$project": doc! {"_id": 0, "bucket": "$_id.bucket", "series": "$_id.series", "count": 1}},
+            ];
+        }
+    };
+    vec![
+        doc! {"$group": doc! {
+            "_id": doc! {"bucket": bucket, "series": key},
+            "count": doc! {"$sum": 1},
+        }},
+        doc! {"$project": doc! {"_id": 0, "bucket": "$_id.bucket", "series": "$_id.series", "count": 1}},
+    ]
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BucketCount {
+    pub(crate) bucket: bson::DateTime,
+    pub(crate) series: String,
+    pub(crate) count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Timeline {
+    pub bucket_size: BucketSize,
+    // * start of each bucket, RFC 3339 with the offset of the zone
+    pub buckets: Vec<String>,
+    // * one count per bucket for each series, zero filled
+    pub series: BTreeMap<String, Vec<i64>>,
+    #[serde(skip)]
+    starts: Vec<DateTime<Tz>>,
+}
+
+impl Timeline {
+    pub(crate) fn new(
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        size: BucketSize,
+        zone: Tz,
+        counts: Vec<BucketCount>,
+    ) -> anyhow::Result<Timeline> {
+        let mut starts: Vec<DateTime<Utc>> = vec![];
+        let mut bucket = size.start(start, zone);
+        while bucket < end {
+            if starts.len() as i64 == MAX_BUCKETS {
+                bail!("Too many buckets; use a larger --bucket or a shorter range");
+            }
+            starts.push(bucket);
+            bucket = size.next(bucket, zone);
+        }
+        let index: HashMap<i64, usize> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.timestamp_millis(), i))
+            .collect();
+        let mut series: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+        for bc in counts {
+            let slots = series
+                .entry(bc.series)
+                .or_insert_with(|| vec![0; starts.len()]);
+            if let Some(&i) = index.get(&bc.bucket.timestamp_millis()) {
+                slots[i] += bc.count;
+            }
+        }
+        let starts: Vec<DateTime<Tz>> = starts.iter().map(|t| t.with_timezone(&zone)).collect();
+        Ok(Timeline {
+            bucket_size: size,
+            buckets: starts.iter().map(|t| t.to_rfc3339()).collect(),
+            series,
+            starts,
+        })
+    }
+
+    fn totals(&self) -> Vec<i64> {
+        (0..self.starts.len())
+            .map(|i| self.series.values().map(|counts| counts[i]).sum())
+            .collect()
+    }
+
+    fn print_bars(&self) {
+        let totals = self.totals();
+        let max = totals.iter().copied().max().unwrap_or(0);
+        for (i, start) in self.starts.iter().enumerate() {
+            let mut line = format!(
+                "{}  {}  {:>7}",
+                style(self.bucket_size.label(start)).yellow(),
+                bar(totals[i], max, BAR_WIDTH),
+                totals[i]
+            );
+            if self.series.len() > 1 {
+                for (name, counts) in &self.series {
+                    if counts[i] > 0 {
+                        line.push_str(&format!("  {}: {}", style(name).red(), counts[i]));
+                    }
+                }
+            }
+            println!("{line}");
+        }
+    }
+
+    pub(crate) fn print_sparklines(&self) {
+        let width = self.series.keys().map(String::len).max().unwrap_or(0);
+        if let (Some(first), Some(last)) = (self.starts.first(), self.starts.last()) {
+            println!(
+                "{} to {}",
+                style(self.bucket_size.label(first)).yellow(),
+                style(self.bucket_size.label(last)).yellow()
+            );
+        }
+        for (name, counts) in &self.series {
+            let total: i64 = counts.iter().sum();
+            println!(
+                "{:>width$}  {}  {}",
+                style(name).red(),
+                sparkline(counts),
+                total
+            );
+        }
+    }
+
+    // * wide form for spreadsheets: a time column then one column per series
+    fn rows(&self) -> (Vec<&str>, Vec<Vec<String>>) {
+        let mut header = vec!["time"];
+        header.extend(self.series.keys().map(String::as_str));
+        let rows = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, time)| {
+                let mut row = vec![time.clone()];
+                row.extend(self.series.values().map(|counts| counts[i].to_string()));
+                row
+            })
+            .collect();
+        (header, rows)
+    }
+}
+
+#[derive(Serialize)]
+struct BucketRecord<'a> {
+    time: &'a str,
+    series: &'a str,
+    count: i64,
+}
+
+pub fn sparkline(counts: &[i64]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    counts
+        .iter()
+        .map(|&count| match count {
+            0 => ' ',
+            _ => SPARKS[((count * 8 - 1) / max).clamp(0, 7) as usize],
+        })
+        .collect()
+}
+
+// * horizontal bar of count relative to max, in eighths of a character
+pub fn bar(count: i64, max: i64, width: usize) -> String {
+    let eighths = if max > 0 {
+        (count * width as i64 * 8 / max) as usize
+    } else {
+        0
+    };
+    let mut bar = "█".repeat(eighths / 8);
+    if eighths % 8 > 0 {
+        bar.push(EIGHTHS[eighths % 8]);
+    }
+    format!("{bar:<width$}")
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn timeline(
+    range: &RangeArgs,
+    filters: &EntryFilters,
+    size: BucketSize,
+    series: &Series,
+    chart: Chart,
+    format: OutputFormat,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let (start, end) = range.resolve(Utc::now())?;
+    let zone = range.zone();
+    let date_range = query::DateRange {
+        start: start.into(),
+        end: end.into(),
+    };
+    let (_, _, logents_coll) = crate::setup_db(config).await?;
+    let stages = query::entry_stages(&date_range, filters);
+    let counts: Vec<BucketCount> = logents_coll
+        .aggregate(pipeline(stages, size, zone, series), None)
+        .await?
+        .with_type::<BucketCount>()
+        .try_collect()
+        .await?;
+    let mut timeline = Timeline::new(start, end, size, zone, counts)?;
+    if timeline.series.is_empty() {
+        let name = match series {
+            Series::Ip(ip) => ip.clone(),
+            _ => "all".to_string(),
+        };
+        timeline.series.insert(name, vec![0; timeline.starts.len()]);
+    }
+    let mut out = std::io::stdout().lock();
+    match (format, chart) {
+        (OutputFormat::Table, Chart::Bars) => timeline.print_bars(),
+        (OutputFormat::Table, Chart::Sparkline) => timeline.print_sparklines(),
+        (OutputFormat::Json, _) => output::write_json(&mut out, &[&timeline])?,
+        (OutputFormat::Ndjson, _) => {
+            let records: Vec<BucketRecord> = timeline
+                .series
+                .iter()
+                .flat_map(|(name, counts)| {
+                    timeline
+                        .buckets
+                        .iter()
+                        .zip(counts)
+                        .map(move |(time, &count)| BucketRecord {
+                            time,
+                            series: name,
+                            count,
+                        })
+                })
+                .collect();
+            output::write_ndjson(&mut out, &records)?
+        }
+        (OutputFormat::Csv, _) => {
+            let (header, rows) = timeline.rows();
+            output::write_csv(&mut out, &header, rows)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(spec: &str) -> DateTime<Utc> {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn buckets_are_zero_filled() {
+        let counts = vec![
+            BucketCount {
+                bucket: at("2024-12-09T10:00:00Z").into(),
+                series: "4xx".to_string(),
+                count: 3,
+            },
+            BucketCount {
+                bucket: at("2024-12-09T12:00:00Z").into(),
+                series: "2xx".to_string(),
+                count: 5,
+            },
+        ];
+        let timeline = Timeline::new(
+            at("2024-12-09T10:30:00Z"),
+            at("2024-12-09T13:00:00Z"),
+            BucketSize::Hour,
+            chrono_tz::UTC,
+            counts,
+        )
+        .unwrap();
+        assert_eq!(timeline.buckets.len(), 3);
+        assert_eq!(timeline.series["4xx"], [3, 0, 0]);
+        assert_eq!(timeline.series["2xx"], [0, 0, 5]);
+        assert_eq!(timeline.totals(), [3, 0, 5]);
+        let (header, rows) = timeline.rows();
+        assert_eq!(header, ["time", "2xx", "4xx"]);
+        assert_eq!(rows[2][1], "5");
+    }
+
+    #[test]
+    fn buckets_follow_the_zone() {
+        let berlin = chrono_tz::Europe::Berlin;
+        let counts = vec![BucketCount {
+            bucket: at("2024-10-26T22:00:00Z").into(),
+            series: "all".to_string(),
+            count: 2,
+        }];
+        // * the day clocks go back is 25 hours long
+        let timeline = Timeline::new(
+            at("2024-10-26T12:00:00Z"),
+            at("2024-10-28T12:00:00Z"),
+            BucketSize::Day,
+            berlin,
+            counts,
+        )
+        .unwrap();
+        assert_eq!(
+            timeline.buckets,
+            [
+                "2024-10-26T00:00:00+02:00",
+                "2024-10-27T00:00:00+02:00",
+                "2024-10-28T00:00:00+01:00"
+            ]
+        );
+        assert_eq!(timeline.series["all"], [0, 2, 0]);
+        assert_eq!(BucketSize::Day.label(&timeline.starts[1]), "2024-10-27");
+        let kolkata = chrono_tz::Asia::Kolkata;
+        let start = BucketSize::Hour.start(at("2024-12-09T10:10:00Z"), kolkata);
+        assert_eq!(start, at("2024-12-09T09:30:00Z"));
+        assert_eq!(
+            bucket_start(BucketSize::Hour, kolkata),
+            doc! {"$dateTrunc": {"date": "$time", "unit": "hour", "timezone": "Asia/Kolkata"}}
+        );
+    }
+
+    #[test]
+    fn too_many_buckets() {
+        let timeline = Timeline::new(
+            at("2020-01-01T00:00:00Z"),
+            at("2024-01-01T00:00:00Z"),
+            BucketSize::Minute,
+            chrono_tz::UTC,
+            vec![],
+        );
+        assert!(timeline.is_err());
+    }
+
+    #[test]
+    fn charts() {
+        assert_eq!(sparkline(&[0, 1, 8]), " ▁█");
+        assert_eq!(bar(4, 8, 4), "██  ");
+        assert_eq!(bar(4, 16, 2), "▌ ");
+    }
+}
@@ -0,0 +1,322 @@
+// * parsing of human friendly time specs used on the command line
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::query::DateRange;
+
+// * parse a span like 90d, 24h, 30m, 45s or 2w into a chrono Duration
+pub fn parse_duration(spec: &str) -> anyhow::Result<Duration> {
+    let spec = spec.trim();
+    let split_at = spec.find(|c: char| !c.is_ascii_digit()).ok_or(anyhow!(
+        "Missing unit in duration {spec:?}, e.g. 90d or 24h"
+    ))?;
+    let (num_str, unit) = spec.split_at(split_at);
+    if num_str.is_empty() {
+        bail!("Missing number in duration {spec:?}, e.g. 90d or 24h");
+    }
+    let n: i64 = num_str.parse()?;
+    let duration = match unit {
+        "s" => Duration::try_seconds(n),
+        "m" => Duration::try_minutes(n),
+        "h" => Duration::try_hours(n),
+        "d" => Duration::try_days(n),
+        "w" => Duration::try_weeks(n),
+        _ => bail!("Unknown unit {unit:?} in duration {spec:?}; use s, m, h, d or w"),
+    };
+    duration.ok_or(anyhow!("Duration {spec:?} is too long"))
+}
+
+// * a time range for commands that search stored entries; the end defaults to now
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct RangeArgs {
+    /// start time, e.g. 2023-12-29T00:00:00Z or, in --tz, 2023-12-29 14:00
+    #[clap(long, short, conflicts_with_all = ["since", "on", "today"])]
+    pub start: Option<String>,
+
+    /// end time, same forms as --start; default now
+    #[clap(long, short, conflicts_with_all = ["on", "today"])]
+    pub end: Option<String>,
+
+    /// time span back from now, e.g. 24h or 7d
+    #[clap(long, visible_alias = "last", conflicts_with_all = ["on", "today"])]
+    pub since: Option<String>,
+
+    /// a single day, e.g. 2024-12-09, in --tz
+    #[clap(long, conflicts_with = "today")]
+    pub on: Option<String>,
+
+    /// today so far, in --tz
+    #[clap(long)]
+    pub today: bool,
+
+    /// timezone for times without an offset, e.g. Europe/Berlin; default local time
+    #[clap(long)]
+    pub tz: Option<Tz>,
+}
+
+const NAIVE_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+];
+
+fn localize<Z: TimeZone>(zone: &Z, naive: NaiveDateTime) -> anyhow::Result<DateTime<Utc>> {
+    zone.from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or(anyhow!(
+            "{naive} does not exist in the timezone (clocks skipped it)"
+        ))
+}
+
+fn parse_date(spec: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(spec.trim(), "%Y-%m-%d")
+        .map_err(|_| anyhow!("Bad date {spec:?}; expected e.g. 2024-12-09"))
+}
+
+// * a span back from now; a long enough span leaves the representable range of times
+fn before(now: DateTime<Utc>, ago: Duration) -> anyhow::Result<DateTime<Utc>> {
+    now.checked_sub_signed(ago)
+        .ok_or(anyhow!("Time range out of bounds: {ago} before {now}"))
+}
+
+impl RangeArgs {
+    fn localize(&self, naive: NaiveDateTime) -> anyhow::Result<DateTime<Utc>> {
+        match &self.tz {
+            Some(tz) => localize(tz, naive),
+            None => localize(&Local, naive),
+        }
+    }
+
+    fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        match &self.tz {
+            Some(tz) => now.with_timezone(tz).date_naive(),
+            None => now.with_timezone(&Local).date_naive(),
+        }
+    }
+
+    // * --tz, else the system zone; UTC if the system zone has no IANA name
+    pub fn zone(&self) -> Tz {
+        self.tz.unwrap_or_else(|| {
+            iana_time_zone::get_timezone()
+                .ok()
+                .and_then(|name| name.parse().ok())
+                .unwrap_or(chrono_tz::UTC)
+        })
+    }
+
+    // * RFC 3339 carries its own offset; other forms are taken in --tz
+    fn parse_time(&self, spec: &str) -> anyhow::Result<DateTime<Utc>> {
+        let spec = spec.trim();
+        if let Ok(t) = DateTime::parse_from_rfc3339(spec) {
+            return Ok(t.with_timezone(&Utc));
+        }
+        if let Some(naive) = NAIVE_FORMATS
+            .iter()
+            .find_map(|fmt| NaiveDateTime::parse_from_str(spec, fmt).ok())
+        {
+            return self.localize(naive);
+        }
+        match parse_date(spec) {
+            Ok(date) => self.localize(date.and_time(chrono::NaiveTime::MIN)),
+            Err(_) => bail!(
+                "Bad time {spec:?}; expected e.g. 2024-12-09T14:00:00Z, 2024-12-09 14:00 or 2024-12-09"
+            ),
+        }
+    }
+
+    fn day(&self, date: NaiveDate) -> anyhow::Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let start = self.localize(date.and_time(chrono::NaiveTime::MIN))?;
+        let next = date.succ_opt().ok_or(anyhow!("Date {date} out of range"))?;
+        Ok((start, self.localize(next.and_time(chrono::NaiveTime::MIN))?))
+    }
+
+    pub fn resolve(&self, now: DateTime<Utc>) -> anyhow::Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let (start, end) = if self.today {
+            (self.day(self.today(now))?.0, now)
+        } else if let Some(on) = &self.on {
+            self.day(parse_date(on)?)?
+        } else {
+            let start = match (&self.start, &self.since) {
+                (Some(start), _) => self.parse_time(start)?,
+                (None, Some(since)) => before(now, parse_duration(since)?)?,
+                (None, None) => bail!("Give a time range with --start, --since, --on or --today"),
+            };
+            let end = match &self.end {
+                Some(end) => self.parse_time(end)?,
+                None => now,
+            };
+            (start, end)
+        };
+        if start >= end {
+            bail!("Time range is empty: {start} is not before {end}");
+        }
+        Ok((start, end))
+    }
+
+    // * a point in a range spec: a span back from now, e.g. 7d, or a time
+    fn point(&self, spec: &str, now: DateTime<Utc>) -> anyhow::Result<DateTime<Utc>> {
+        match parse_duration(spec) {
+            Ok(ago) => before(now, ago),
+            Err(_) => self.parse_time(spec),
+        }
+    }
+
+    // * a range in one argument, in --tz: "today", a day like 2024-12-09, a span back
+    // * from now like 7d, or START..END where either side is a time or a span back from
+    // * now and END defaults to now, e.g. 14d..7d for the week before last
+    pub fn resolve_spec(
+        &self,
+        spec: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let spec = spec.trim();
+        let (start, end) = if spec == "today" {
+            (self.day(self.today(now))?.0, now)
+        } else if let Some((start, end)) = spec.split_once("..") {
+            if start.trim().is_empty() {
+                bail!("Range {spec:?} has no start");
+            }
+            let end = match end.trim() {
+                "" => now,
+                end => self.point(end, now)?,
+            };
+            (self.point(start, now)?, end)
+        } else if let Ok(ago) = parse_duration(spec) {
+            (before(now, ago)?, now)
+        } else if let Ok(date) = parse_date(spec) {
+            self.day(date)?
+        } else {
+            bail!("Bad range {spec:?}; expected e.g. today, 2024-12-09, 7d, 14d..7d or START..END")
+        };
+        if start >= end {
+            bail!("Time range is empty: {start} is not before {end}");
+        }
+        Ok((start, end))
+    }
+
+    pub fn date_range(&self) -> anyhow::Result<DateRange> {
+        let (start, end) = self.resolve(Utc::now())?;
+        Ok(DateRange {
+            start: start.into(),
+            end: end.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_test() {
+        assert_eq!(parse_duration("90d").unwrap(), Duration::days(90));
+        assert_eq!(parse_duration("24h").unwrap(), Duration::hours(24));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_duration(" 30m ").unwrap(), Duration::minutes(30));
+    }
+
+    #[test]
+    fn parse_bad_duration_test() {
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("99999999999999d").is_err());
+    }
+
+    fn at(spec: &str) -> DateTime<Utc> {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn range_forms() {
+        let now = at("2024-12-09T15:30:00Z");
+        let berlin = RangeArgs {
+            tz: Some(chrono_tz::Europe::Berlin),
+            ..Default::default()
+        };
+        let since = RangeArgs {
+            since: Some("24h".to_string()),
+            ..berlin.clone()
+        };
+        assert_eq!(
+            since.resolve(now).unwrap(),
+            (at("2024-12-08T15:30:00Z"), now)
+        );
+        let on = RangeArgs {
+            on: Some("2024-12-09".to_string()),
+            ..berlin.clone()
+        };
+        assert_eq!(
+            on.resolve(now).unwrap(),
+            (at("2024-12-08T23:00:00Z"), at("2024-12-09T23:00:00Z"))
+        );
+        let today = RangeArgs {
+            today: true,
+            ..berlin.clone()
+        };
+        assert_eq!(today.resolve(now).unwrap().0, at("2024-12-08T23:00:00Z"));
+        let local = RangeArgs {
+            start: Some("2024-12-09 14:00".to_string()),
+            ..berlin.clone()
+        };
+        assert_eq!(
+            local.resolve(now).unwrap(),
+            (at("2024-12-09T13:00:00Z"), now)
+        );
+    }
+
+    #[test]
+    fn range_specs() {
+        let now = at("2024-12-09T15:30:00Z");
+        let utc = RangeArgs {
+            tz: Some(chrono_tz::UTC),
+            ..Default::default()
+        };
+        assert_eq!(
+            utc.resolve_spec("14d..7d", now).unwrap(),
+            (at("2024-11-25T15:30:00Z"), at("2024-12-02T15:30:00Z"))
+        );
+        assert_eq!(
+            utc.resolve_spec("7d", now).unwrap(),
+            (at("2024-12-02T15:30:00Z"), now)
+        );
+        assert_eq!(
+            utc.resolve_spec("2024-12-01", now).unwrap(),
+            (at("2024-12-01T00:00:00Z"), at("2024-12-02T00:00:00Z"))
+        );
+        assert_eq!(
+            utc.resolve_spec("2024-12-01T12:00:00Z..", now).unwrap(),
+            (at("2024-12-01T12:00:00Z"), now)
+        );
+        assert!(utc.resolve_spec("7d..14d", now).is_err());
+        assert!(utc.resolve_spec("..7d", now).is_err());
+        assert!(utc.resolve_spec("last week", now).is_err());
+    }
+
+    #[test]
+    fn range_out_of_bounds() {
+        let now = at("2024-12-09T15:30:00Z");
+        let since = RangeArgs {
+            since: Some("100000000d".to_string()),
+            ..Default::default()
+        };
+        assert!(since.resolve(now).is_err());
+        let range = RangeArgs::default();
+        assert!(range.resolve_spec("100000000d", now).is_err());
+        assert!(range.resolve_spec("100000000d..7d", now).is_err());
+    }
+
+    #[test]
+    fn range_needs_start() {
+        assert!(RangeArgs::default().resolve(Utc::now()).is_err());
+        let backwards = RangeArgs {
+            start: Some("2024-12-09T00:00:00Z".to_string()),
+            end: Some("2024-12-08T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        assert!(backwards.resolve(Utc::now()).is_err());
+    }
+}
@@ -0,0 +1,595 @@
+// * export and import of logentries and hostdata as ndjson, csv or parquet files
+// * an export directory holds logentries.<ext> and hostdata.<ext>
+use anyhow::{anyhow, Context};
+use futures::stream::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{AggregateOptions, FindOptions, InsertManyOptions};
+use mongodb::{Collection, Database};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::SerializedFileReader;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::{Row, RowAccessor};
+use parquet::schema::parser::parse_message_type;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::geo::Geodata;
+use crate::log_entries::LogEntry;
+use crate::{query, rollup, Config, HostData};
+
+// * rows per insert_many call on import and per parquet row group on export
+const BATCH_SIZE: usize = 1000;
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum FileFormat {
+    Ndjson,
+    Csv,
+    Parquet,
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Ndjson => "ndjson",
+            FileFormat::Csv => "csv",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+}
+
+enum ColumnValues {
+    Str(Vec<ByteArray>),
+    Long(Vec<i64>),
+}
+
+fn str_column<R>(rows: &[R], get: impl Fn(&R) -> &str) -> ColumnValues {
+    ColumnValues::Str(rows.iter().map(|row| get(row).into()).collect())
+}
+
+// * flat record layout shared by all file formats
+trait Record: Serialize + DeserializeOwned {
+    const NAME: &'static str;
+    const PARQUET_SCHEMA: &'static str;
+    fn columns(rows: &[Self]) -> anyhow::Result<Vec<ColumnValues>>;
+    fn from_row(row: &Row) -> anyhow::Result<Self>;
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogEntryRecord {
+    pub ip: String,
+    pub time: String,
+    pub method: String,
+    pub code: u32,
+    pub nbytes: u32,
+    pub referrer: String,
+    pub ua: String,
+    pub line: String,
+}
+
+impl From<&LogEntry> for LogEntryRecord {
+    fn from(le: &LogEntry) -> Self {
+        LogEntryRecord {
+            ip: le.ip.clone(),
+            time: le.time.to_chrono().to_rfc3339(),
+            method: le.method.clone(),
+            code: le.code,
+            nbytes: le.nbytes,
+            referrer: le.referrer.clone(),
+            ua: le.ua.clone(),
+            line: le.line.clone(),
+        }
+    }
+}
+
+impl TryFrom<LogEntryRecord> for LogEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(rec: LogEntryRecord) -> anyhow::Result<Self> {
+        let time: crate::Logdate = chrono::DateTime::parse_from_rfc3339(&rec.time)
+            .with_context(|| format!("Bad time {:?} for ip {}", rec.time, rec.ip))?
+            .into();
+        Ok(LogEntry {
+            ip_key: crate::cidr::ip_key(&rec.ip),
+            ip: rec.ip,
+            time: time.into(),
+            method: rec.method,
+            code: rec.code,
+            nbytes: rec.nbytes,
+            referrer: rec.referrer,
+            ua: rec.ua,
+            line: rec.line,
+        })
+    }
+}
+
+impl Record for LogEntryRecord {
+    const NAME: &'static str = "logentries";
+    const PARQUET_SCHEMA: &'static str = "message logentry {
+        REQUIRED BYTE_ARRAY ip (UTF8);
+        REQUIRED INT64 time (TIMESTAMP(MILLIS,true));
+        REQUIRED BYTE_ARRAY method (UTF8);
+        REQUIRED INT64 code;
+        REQUIRED INT64 nbytes;
+        REQUIRED BYTE_ARRAY referrer (UTF8);
+        REQUIRED BYTE_ARRAY ua (UTF8);
+        REQUIRED BYTE_ARRAY line (UTF8);
+    }";
+
+    fn columns(rows: &[Self]) -> anyhow::Result<Vec<ColumnValues>> {
+        let times = rows
+            .iter()
+            .map(|row| {
+                chrono::DateTime::parse_from_rfc3339(&row.time)
+                    .map(|t| t.timestamp_millis())
+                    .map_err(|_| anyhow!("Bad time {:?} in {} record", row.time, Self::NAME))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(vec![
+            str_column(rows, |r| &r.ip),
+            ColumnValues::Long(times),
+            str_column(rows, |r| &r.method),
+            ColumnValues::Long(rows.iter().map(|r| r.code.into()).collect()),
+            ColumnValues::Long(rows.iter().map(|r| r.nbytes.into()).collect()),
+            str_column(rows, |r| &r.referrer),
+            str_column(rows, |r| &r.ua),
+            str_column(rows, |r| &r.line),
+        ])
+    }
+
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        let millis = row.get_timestamp_millis(1)?;
+        let time = bson::DateTime::from_millis(millis).to_chrono().to_rfc3339();
+        Ok(LogEntryRecord {
+            ip: row.get_string(0)?.clone(),
+            time,
+            method: row.get_string(2)?.clone(),
+            code: row.get_long(3)?.try_into()?,
+            nbytes: row.get_long(4)?.try_into()?,
+            referrer: row.get_string(5)?.clone(),
+            ua: row.get_string(6)?.clone(),
+            line: row.get_string(7)?.clone(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct HostDataRecord {
+    pub ip: String,
+    pub country_name: String,
+    pub state_prov: String,
+    pub city: String,
+    pub isp: String,
+    pub organization: String,
+    // * semicolon separated so that csv and parquet stay flat
+    pub ptr_records: String,
+}
+
+impl From<&HostData> for HostDataRecord {
+    fn from(hd: &HostData) -> Self {
+        HostDataRecord {
+            ip: hd.ip.clone(),
+            country_name: hd.geodata.country_name.clone(),
+            state_prov: hd.geodata.state_prov.clone(),
+            city: hd.geodata.city.clone(),
+            isp: hd.geodata.isp.clone(),
+            organization: hd.geodata.organization.clone(),
+            ptr_records: hd.ptr_records.join(";"),
+        }
+    }
+}
+
+impl From<HostDataRecord> for HostData {
+    fn from(rec: HostDataRecord) -> Self {
+        HostData {
+            ip_key: crate::cidr::ip_key(&rec.ip),
+            ip: rec.ip.clone(),
+            geodata: Geodata {
+                ip: rec.ip,
+                country_name: rec.country_name,
+                state_prov: rec.state_prov,
+                city: rec.city,
+                isp: rec.isp,
+                organization: rec.organization,
+            },
+            ptr_records: rec
+                .ptr_records
+                .split(';')
+                .filter(|record| !record.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl Record for HostDataRecord {
+    const NAME: &'static str = "hostdata";
+    const PARQUET_SCHEMA: &'static str = "message hostdata {
+        REQUIRED BYTE_ARRAY ip (UTF8);
+        REQUIRED BYTE_ARRAY country_name (UTF8);
+        REQUIRED BYTE_ARRAY state_prov (UTF8);
+        REQUIRED BYTE_ARRAY city (UTF8);
+        REQUIRED BYTE_ARRAY isp (UTF8);
+        REQUIRED BYTE_ARRAY organization (UTF8);
+        REQUIRED BYTE_ARRAY ptr_records (UTF8);
+    }";
+
+    fn columns(rows: &[Self]) -> anyhow::Result<Vec<ColumnValues>> {
+        Ok(vec![
+            str_column(rows, |r| &r.ip),
+            str_column(rows, |r| &r.country_name),
+            str_column(rows, |r| &r.state_prov),
+            str_column(rows, |r| &r.city),
+            str_column(rows, |r| &r.isp),
+            str_column(rows, |r| &r.organization),
+            str_column(rows, |r| &r.ptr_records),
+        ])
+    }
+
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(HostDataRecord {
+            ip: row.get_string(0)?.clone(),
+            country_name: row.get_string(1)?.clone(),
+            state_prov: row.get_string(2)?.clone(),
+            city: row.get_string(3)?.clone(),
+            isp: row.get_string(4)?.clone(),
+            organization: row.get_string(5)?.clone(),
+            ptr_records: row.get_string(6)?.clone(),
+        })
+    }
+}
+
+fn file_path<R: Record>(dir: &Path, format: FileFormat) -> PathBuf {
+    dir.join(format!("{}.{}", R::NAME, format.extension()))
+}
+
+enum RecordWriter<R: Record> {
+    Ndjson(BufWriter<File>),
+    Csv(csv::Writer<File>),
+    Parquet(SerializedFileWriter<File>, Vec<R>),
+}
+
+impl<R: Record> RecordWriter<R> {
+    fn create(path: &Path, format: FileFormat) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let writer = match format {
+            FileFormat::Ndjson => RecordWriter::Ndjson(BufWriter::new(file)),
+            FileFormat::Csv => RecordWriter::Csv(csv::Writer::from_writer(file)),
+            FileFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(R::PARQUET_SCHEMA)?);
+                let props = Arc::new(WriterProperties::builder().build());
+                let writer = SerializedFileWriter::new(file, schema, props)?;
+                RecordWriter::Parquet(writer, Vec::with_capacity(BATCH_SIZE))
+            }
+        };
+        Ok(writer)
+    }
+
+    fn write(&mut self, record: R) -> anyhow::Result<()> {
+        match self {
+            RecordWriter::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                writeln!(writer)?;
+            }
+            RecordWriter::Csv(writer) => writer.serialize(record)?,
+            RecordWriter::Parquet(writer, buffer) => {
+                buffer.push(record);
+                if buffer.len() >= BATCH_SIZE {
+                    write_row_group(writer, buffer)?;
+                    buffer.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            RecordWriter::Ndjson(mut writer) => writer.flush()?,
+            RecordWriter::Csv(mut writer) => writer.flush()?,
+            RecordWriter::Parquet(mut writer, buffer) => {
+                if !buffer.is_empty() {
+                    write_row_group(&mut writer, &buffer)?;
+                }
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_row_group<R: Record>(
+    writer: &mut SerializedFileWriter<File>,
+    rows: &[R],
+) -> anyhow::Result<()> {
+    let mut row_group = writer.next_row_group()?;
+    for values in R::columns(rows)? {
+        let mut column = row_group
+            .next_column()?
+            .ok_or(anyhow!("Parquet schema has fewer columns than {}", R::NAME))?;
+        match values {
+            ColumnValues::Str(values) => {
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            ColumnValues::Long(values) => {
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+        }
+        column.close()?;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+fn read_records<R: Record + 'static>(
+    path: &Path,
+    format: FileFormat,
+) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<R>>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let records: Box<dyn Iterator<Item = anyhow::Result<R>>> = match format {
+        FileFormat::Ndjson => Box::new(
+            BufReader::new(file)
+                .lines()
+                .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        FileFormat::Csv => Box::new(
+            csv::Reader::from_reader(file)
+                .into_deserialize()
+                .map(|rec| rec.map_err(anyhow::Error::from)),
+        ),
+        FileFormat::Parquet => Box::new(
+            SerializedFileReader::new(file)?
+                .into_iter()
+                .map(|row| R::from_row(&row?)),
+        ),
+    };
+    Ok(records)
+}
+
+pub async fn export(
+    start: &str,
+    end: &str,
+    format: &FileFormat,
+    dir: &PathBuf,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let date_range = query::time_str_to_daterange(start, end)?;
+    let (_, _, logents_coll) = crate::setup_db(config).await?;
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let path = file_path::<LogEntryRecord>(dir, *format);
+    let mut writer: RecordWriter<LogEntryRecord> = RecordWriter::create(&path, *format)?;
+    let filter = doc! {"time": {"$gte": date_range.start, "$lt": date_range.end}};
+    let options = FindOptions::builder().sort(doc! {"time": 1}).build();
+    let mut curs = logents_coll.find(filter, options).await?;
+    let mut n_logents = 0;
+    while let Some(le) = curs.try_next().await? {
+        writer.write(LogEntryRecord::from(&le))?;
+        n_logents += 1;
+    }
+    writer.finish()?;
+    println!("Exported {n_logents} log entries to {}", path.display());
+
+    // * hostdata of the ips seen in the range, joined server side
+    let path = file_path::<HostDataRecord>(dir, *format);
+    let mut writer: RecordWriter<HostDataRecord> = RecordWriter::create(&path, *format)?;
+    let pipeline = vec![
+        doc! {"$match": {"time": {"$gte": date_range.start, "$lt": date_range.end}}},
+        doc! {"$group": {"_id": "$ip"}},
+        doc! {"$sort": {"_id": 1}},
+        doc! {"$lookup": {
+            "from": "hostdata",
+            "localField": "_id",
+            "foreignField": "ip",
+            "as": "hostdata",
+        }},
+        doc! {"$unwind": "$hostdata"},
+        doc! {"$replaceRoot": {"newRoot": "$hostdata"}},
+    ];
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let mut curs = logents_coll.aggregate(pipeline, options).await?;
+    let mut n_hosts = 0;
+    while let Some(doc) = curs.try_next().await? {
+        let hd: HostData = bson::from_document(doc)?;
+        writer.write(HostDataRecord::from(&hd))?;
+        n_hosts += 1;
+    }
+    writer.finish()?;
+    println!("Exported {n_hosts} hosts to {}", path.display());
+    Ok(())
+}
+
+// * insert a batch, skipping documents rejected by a unique index
+// * returns the indexes in batch of the skipped documents
+pub(crate) async fn insert_batch<T: Serialize>(
+    coll: &Collection<T>,
+    batch: &[T],
+) -> anyhow::Result<Vec<usize>> {
+    if batch.is_empty() {
+        return Ok(vec![]);
+    }
+    let options = InsertManyOptions::builder().ordered(false).build();
+    match coll.insert_many(batch, options).await {
+        Ok(_) => Ok(vec![]),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                let write_errors = failure.write_errors.as_deref().unwrap_or_default();
+                if let Some(err) = write_errors.iter().find(|we| we.code != DUPLICATE_KEY) {
+                    return Err(anyhow!("Insert failed: {}", err.message));
+                }
+                Ok(write_errors.iter().map(|we| we.index).collect())
+            }
+            ErrorKind::Write(WriteFailure::WriteError(we))
+                if we.code == DUPLICATE_KEY && batch.len() == 1 =>
+            {
+                Ok(vec![0])
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+// * follow-up for documents an import added
+trait Imported: Sized {
+    async fn after_insert(_db: &Database, _inserted: &[Self]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl Imported for HostData {}
+
+impl Imported for LogEntry {
+    async fn after_insert(db: &Database, inserted: &[Self]) -> anyhow::Result<()> {
+        rollup::add_to_rollups(db, inserted).await
+    }
+}
+
+// * insert a batch and follow up on the documents that were new; returns (inserted, skipped)
+async fn import_batch<T: Serialize + Imported>(
+    db: &Database,
+    coll: &Collection<T>,
+    batch: &mut Vec<T>,
+) -> anyhow::Result<(usize, usize)> {
+    let skipped = insert_batch(coll, batch).await?;
+    let inserted: Vec<T> = batch
+        .drain(..)
+        .enumerate()
+        .filter(|(i, _)| !skipped.contains(i))
+        .map(|(_, doc)| doc)
+        .collect();
+    T::after_insert(db, &inserted).await?;
+    Ok((inserted.len(), skipped.len()))
+}
+
+async fn import_file<R, T>(
+    path: &Path,
+    format: FileFormat,
+    db: &Database,
+    coll: &Collection<T>,
+) -> anyhow::Result<()>
+where
+    R: Record + 'static,
+    T: Serialize + Imported + TryFrom<R>,
+    anyhow::Error: From<<T as TryFrom<R>>::Error>,
+{
+    let mut inserted = 0;
+    let mut skipped = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for record in read_records::<R>(path, format)? {
+        batch.push(T::try_from(record?)?);
+        if batch.len() >= BATCH_SIZE {
+            let (i, s) = import_batch(db, coll, &mut batch).await?;
+            inserted += i;
+            skipped += s;
+        }
+    }
+    let (i, s) = import_batch(db, coll, &mut batch).await?;
+    inserted += i;
+    skipped += s;
+    println!(
+        "Imported {inserted} {} from {}, skipped {skipped} already present",
+        R::NAME,
+        path.display()
+    );
+    Ok(())
+}
+
+pub async fn import(format: &FileFormat, dir: &Path, config: &Config) -> anyhow::Result<()> {
+    let (db, hostdata_coll, logents_coll) = crate::setup_db(config).await?;
+    let hd_path = file_path::<HostDataRecord>(dir, *format);
+    if hd_path.exists() {
+        import_file::<HostDataRecord, HostData>(&hd_path, *format, &db, &hostdata_coll)
+            .await
+            .with_context(|| format!("Import of {} failed", hd_path.display()))?;
+    }
+    let le_path = file_path::<LogEntryRecord>(dir, *format);
+    if le_path.exists() {
+        import_file::<LogEntryRecord, LogEntry>(&le_path, *format, &db, &logents_coll)
+            .await
+            .with_context(|| format!("Import of {} failed", le_path.display()))?;
+    }
+    if !hd_path.exists() && !le_path.exists() {
+        return Err(anyhow!(
+            "No {} export files found in {}",
+            format.extension(),
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_record() -> LogEntryRecord {
+        LogEntryRecord {
+            ip: "1.2.3.4".to_string(),
+            time: "2024-12-09T10:05:00+00:00".to_string(),
+            method: "GET / HTTP/1.1".to_string(),
+            code: 404,
+            nbytes: 209,
+            referrer: "-".to_string(),
+            ua: "curl/8.0".to_string(),
+            line: "raw, \"quoted\" line".to_string(),
+        }
+    }
+
+    fn round_trip(format: FileFormat) {
+        let dir = std::env::temp_dir().join(format!("loglook-transfer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = file_path::<LogEntryRecord>(&dir, format);
+        let mut writer: RecordWriter<LogEntryRecord> = RecordWriter::create(&path, format).unwrap();
+        writer.write(sample_record()).unwrap();
+        writer.finish().unwrap();
+        let records: Vec<LogEntryRecord> = read_records(&path, format)
+            .unwrap()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records, vec![sample_record()]);
+    }
+
+    #[test]
+    fn bad_time_is_an_error() {
+        let record = LogEntryRecord {
+            time: "yesterday".to_string(),
+            ..sample_record()
+        };
+        assert!(LogEntryRecord::columns(&[sample_record()]).is_ok());
+        assert!(LogEntryRecord::columns(&[record]).is_err());
+    }
+
+    #[test]
+    fn ndjson_round_trip() {
+        round_trip(FileFormat::Ndjson);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip(FileFormat::Csv);
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        round_trip(FileFormat::Parquet);
+    }
+
+    #[test]
+    fn record_to_logentry() {
+        let le = LogEntry::try_from(sample_record()).unwrap();
+        assert_eq!(LogEntryRecord::from(&le), sample_record());
+    }
+}
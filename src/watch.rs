@@ -0,0 +1,101 @@
+// * watch the input logfile so the daemon can ingest soon after nginx writes
+use anyhow::{anyhow, Context};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Instant};
+
+pub struct FileWatcher {
+    // * dropping the watcher stops the events, so keep it alive with the receiver
+    _watcher: RecommendedWatcher,
+    rx: mpsc::UnboundedReceiver<()>,
+}
+
+impl FileWatcher {
+    // * watch the parent directory so that rotated or recreated logfiles are still seen
+    pub fn new(path: &Path) -> anyhow::Result<FileWatcher> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", path.display()))?;
+        let dir = path
+            .parent()
+            .ok_or(anyhow!("Logfile {} has no parent dir", path.display()))?
+            .to_path_buf();
+        let file_name: OsString = path
+            .file_name()
+            .ok_or(anyhow!("Logfile {} has no file name", path.display()))?
+            .to_owned();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                let is_write = matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_));
+                let is_logfile = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(file_name.as_os_str()));
+                if is_write && is_logfile {
+                    // * receiver gone means we are shutting down
+                    let _ = tx.send(());
+                }
+            }
+        })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(FileWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    // * wait up to wait_for for the logfile to change
+    // * once it does, keep collecting events until the file has been quiet for debounce,
+    // * so a burst of writes results in a single ingest; returns false if nothing changed
+    pub async fn changed(&mut self, wait_for: Duration, debounce: Duration) -> bool {
+        match timeout(wait_for, self.rx.recv()).await {
+            Ok(Some(())) => (),
+            _ => return false,
+        }
+        // * a constantly written file must not postpone ingest forever
+        let deadline = Instant::now() + debounce * 10;
+        while Instant::now() < deadline {
+            match timeout(debounce, self.rx.recv()).await {
+                Ok(Some(())) => continue,
+                _ => break,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn detects_appended_lines() {
+        let dir = std::env::temp_dir().join(format!("loglook-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        fs::write(&path, "").unwrap();
+        let mut watcher = FileWatcher::new(&path).unwrap();
+        let short = Duration::from_millis(100);
+        assert!(!aw!(watcher.changed(short, short)));
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "line 1").unwrap();
+        writeln!(file, "line 2").unwrap();
+        assert!(aw!(watcher.changed(Duration::from_secs(2), short)));
+        // * both writes were batched into the one change above
+        assert!(!aw!(watcher.changed(short, short)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}